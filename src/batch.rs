@@ -0,0 +1,235 @@
+use std::{collections::VecDeque, path::{Path, PathBuf}, sync::Mutex, thread};
+
+use crate::{cli, progress::ProgressMode, project::{CutStep, Project, SplitStep, Step}, report::Reporter, template, ExitResult};
+
+/// Default output names used with output directory
+const CUT_NAME: &str = "{stem}.cut{n}.{ext}";
+const SPLIT_NAME: &str = "{stem}.part{index}.{ext}";
+
+/// Run the operation on every input file using a pool of workers, failure of one file does not
+/// stop the others
+pub fn batch_cmd(global: &cli::GlobalArgs, args: cli::BatchArgs) -> ExitResult {
+    let patterns = match &args.op {
+        cli::BatchOperation::Extract(x) => &x.inputs,
+        cli::BatchOperation::Remove(x) => &x.inputs,
+        cli::BatchOperation::Split(x) => &x.inputs,
+        cli::BatchOperation::Project(x) => &x.inputs,
+    };
+
+    let steps = steps_for(&args.op).map_err(|err| {
+        global.report.error(err);
+        1
+    })?;
+
+    let inputs = expand_inputs(patterns, &global.report)?;
+    if inputs.is_empty() {
+        global.report.error("No input files found");
+        return Err(1);
+    }
+
+    let jobs = args.jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |x| x.get()))
+        .clamp(1, inputs.len());
+
+    // steps of the plan would be mixed up between files
+    let jobs = if global.dry_run { 1 } else { jobs };
+
+    eprintln!("Processing {} files using {} workers", inputs.len(), jobs);
+
+    // multiple progress bars would overwrite each other
+    let mut global = global.clone();
+    if jobs > 1 && global.progress == ProgressMode::Bar {
+        global.progress = ProgressMode::None;
+    }
+    let global = &global;
+
+    let total = inputs.len();
+    let queue = Mutex::new(inputs.into_iter().collect::<VecDeque<PathBuf>>());
+    let results: Mutex<Vec<(PathBuf, ExitResult)>> = Mutex::new(vec![]);
+
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                // do not start any new files after interrupt
+                if global.cancel.is_cancelled() {
+                    break;
+                }
+
+                // the lock is released before the work starts
+                let Some(source) = queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+
+                let result = run_operation(global, &args, &steps, &source);
+
+                match result {
+                    Ok(_) => eprintln!("(OK) {:?}", source),
+                    Err(x) => global.report.error(format!("(FAILED) {:?} exited with code {}", source, x)),
+                }
+
+                results.lock().unwrap().push((source, result));
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    let failed: Vec<&PathBuf> = results.iter()
+        .filter(|(_, result)| result.is_err())
+        .map(|(source, _)| source)
+        .collect();
+
+    let succeeded = results.len() - failed.len();
+    eprintln!("Finished {} files, {} succeeded, {} failed", total, succeeded, failed.len());
+    for source in &failed {
+        eprintln!("  failed: {:?}", source);
+    }
+
+    if results.len() < total {
+        eprintln!("  {} skipped due to interrupt", total - results.len());
+    }
+
+    global.cancel.check()?;
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(1)
+    }
+}
+
+/// Expand glob patterns into list of files, inputs without any glob characters are used as is
+fn expand_inputs(patterns: &[String], report: &Reporter) -> Result<Vec<PathBuf>, u8> {
+    let mut inputs: Vec<PathBuf> = vec![];
+
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            inputs.push(PathBuf::from(pattern));
+            continue;
+        }
+
+        let paths = match glob::glob(pattern) {
+            Ok(x) => x,
+            Err(err) => {
+                report.error(format!("Invalid glob pattern {:?}: {}", pattern, err));
+                return Err(1);
+            }
+        };
+
+        let count = inputs.len();
+        inputs.extend(paths.filter_map(Result::ok).filter(|x| x.is_file()));
+
+        if count == inputs.len() {
+            report.warning(format!("pattern {:?} did not match any files", pattern));
+        }
+    }
+
+    // same file could be matched by multiple patterns
+    let mut seen = std::collections::HashSet::new();
+    inputs.retain(|x| seen.insert(x.clone()));
+
+    Ok(inputs)
+}
+
+/// Output template for each file, the template is put into the output directory if one is set
+///
+/// Returns `None` if neither is set so the default of each operation is used
+fn output_for(template: Option<&str>, output_dir: Option<&str>, default_name: &str) -> Option<String> {
+    match (template, output_dir) {
+        (None, None) => None,
+        (Some(template), None) => Some(template.to_string()),
+        (template, Some(dir)) => Some(
+            Path::new(&template::escape(dir))
+                .join(template.unwrap_or(default_name))
+                .to_string_lossy()
+                .to_string()
+        ),
+    }
+}
+
+/// Steps run on each file, project file is read once for all of them
+fn steps_for(op: &cli::BatchOperation) -> Result<Vec<Step>, String> {
+    let cut = |align_keyframe, start, end| CutStep { start, end, align_keyframe, output: None };
+
+    Ok(match op {
+        cli::BatchOperation::Extract(x) => vec![Step::Extract(cut(x.align_keyframe, x.start_time, x.end_time))],
+        cli::BatchOperation::Remove(x) => vec![Step::Remove(cut(x.align_keyframe, x.start_time, x.end_time))],
+        cli::BatchOperation::Split(x) => vec![Step::Split(SplitStep {
+            time: x.group.time,
+            interval: x.group.interval,
+            align_keyframe: x.align_keyframe,
+            output: None,
+        })],
+        cli::BatchOperation::Project(x) => Project::read(Path::new(&x.project))?.steps,
+    })
+}
+
+/// Run the steps one after another, the file fails at the first step that fails
+fn run_operation(global: &cli::GlobalArgs, args: &cli::BatchArgs, steps: &[Step], source: &Path) -> ExitResult {
+    let source_str = source.to_string_lossy().to_string();
+    let output_dir = args.output_dir.as_deref();
+
+    for step in steps {
+        match step {
+            Step::Extract(x) => crate::extract_video_cmd(global, cli::ExtractArgs {
+                align_keyframe: x.align_keyframe,
+                source: source_str.clone(),
+                start_time: x.start,
+                end_time: x.end,
+                output: output_for(x.output.as_deref().or(args.output.as_deref()), output_dir, CUT_NAME),
+            }),
+            Step::Remove(x) => crate::remove_video_cmd(global, cli::RemoveArgs {
+                align_keyframe: x.align_keyframe,
+                source: source_str.clone(),
+                start_time: x.start,
+                end_time: x.end,
+                output: output_for(x.output.as_deref().or(args.output.as_deref()), output_dir, CUT_NAME),
+            }),
+            Step::Split(x) => crate::split_video_cmd(global, cli::SplitArgs {
+                align_keyframe: x.align_keyframe,
+                hls: false,
+                hls_type: Default::default(),
+                source: source_str.clone(),
+                group: cli::TimeOrIntervalGroup { time: x.time, interval: x.interval },
+                output: output_for(x.output.as_deref().or(args.output.as_deref()), output_dir, SPLIT_NAME),
+            }),
+        }?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_for() {
+        assert_eq!(output_for(None, None, CUT_NAME), None);
+        assert_eq!(output_for(Some("{stem}.mkv"), None, CUT_NAME), Some("{stem}.mkv".to_string()));
+
+        assert_eq!(
+            output_for(None, Some("/out"), CUT_NAME),
+            Some("/out/{stem}.cut{n}.{ext}".to_string())
+        );
+
+        assert_eq!(
+            output_for(Some("{stem}-{label}.mkv"), Some("/out/{x}"), CUT_NAME),
+            Some("/out/{{x}}/{stem}-{label}.mkv".to_string())
+        );
+    }
+
+    #[test]
+    fn test_steps_for() {
+        let op = cli::BatchOperation::Remove(cli::BatchRemoveArgs {
+            align_keyframe: true,
+            start_time: 1,
+            end_time: 2,
+            inputs: vec![],
+        });
+
+        assert_eq!(steps_for(&op), Ok(vec![Step::Remove(CutStep { start: 1, end: 2, align_keyframe: true, output: None })]));
+
+        let op = cli::BatchOperation::Project(cli::BatchProjectArgs { project: "/nonexistent/project.toml".into(), inputs: vec![] });
+        assert!(steps_for(&op).is_err());
+    }
+}
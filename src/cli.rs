@@ -1,17 +1,125 @@
+use std::sync::Arc;
+
 use clap::{Parser, Subcommand, Args};
 
+use crate::{config::Config, plan::{Plan, PlanFormat}, preset::Preset, progress::ProgressMode, report::Reporter, util::CancelToken, video::{parse_stream_tag, parse_tag, HlsSegmentType, SnapMode}};
+
 /// Wrapper around ffmpeg to do media file editing with minimal transcoding when possible
 #[derive(Parser, Debug)]
 #[command(name = "rcut", author, version, about)]
 pub struct Cli {
-    /// Just print commands that would've been ran, do not modify filesystem
-    #[arg(long)]
-    pub dry_run: bool,
+    #[command(flatten)]
+    pub global: GlobalArgs,
 
     #[command(subcommand)]
     pub cmd: CliCommands,
 }
 
+/// Options that apply to every command
+#[derive(Args, Debug, Clone, Default)]
+pub struct GlobalArgs {
+    /// Print plan of the commands that would've been ran, do not modify filesystem
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Format of the plan printed in dry run
+    #[arg(long, value_enum, default_value_t)]
+    pub plan_format: PlanFormat,
+
+    /// Print result of the command as JSON on stdout: outputs, cut spans, re-encoded length,
+    /// warnings and errors (and the plan in dry run)
+    #[arg(long)]
+    pub json: bool,
+
+    /// Overwrite outputs that already exist
+    #[arg(long)]
+    pub force: bool,
+
+    /// Directory for temporary files (defaults to system temp directory)
+    #[arg(long, env = "RCUT_TMP_DIR")]
+    pub tmp_dir: Option<String>,
+
+    /// Label used for {label} in output templates
+    #[arg(long)]
+    pub label: Option<String>,
+
+    /// How to report progress of ffmpeg
+    #[arg(long, value_enum, default_value_t)]
+    pub progress: ProgressMode,
+
+    /// Encoding preset used when video has to be transcoded, built in presets are fast, balanced,
+    /// quality, hevc and vp9, more can be defined in the config (defaults to what ffmpeg chooses)
+    #[arg(long)]
+    pub preset: Option<String>,
+
+    /// Verify A/V sync of every output after it is written, problems make the command fail
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Check that every output is as long as expected and decodes without errors after it is
+    /// written, problems make the command fail
+    #[arg(long)]
+    pub validate: bool,
+
+    /// Warn when aligning to keyframes moves either end of a cut by more than this
+    #[arg(long, value_parser = parse_time, default_value = "1s")]
+    pub warn_extra: u64,
+
+    /// How cuts and split points are moved to keyframes, implies aligning to keyframes
+    /// (defaults to outward)
+    #[arg(long, value_enum)]
+    pub snap: Option<SnapMode>,
+
+    /// Keep the original timestamps of the source in cut outputs instead of starting them at
+    /// zero, some players show a blank picture until the first timestamp
+    #[arg(long)]
+    pub keep_timestamps: bool,
+
+    /// Set global tag of outputs, can be used multiple times (like --set-meta title="My video")
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub set_meta: Vec<(String, String)>,
+
+    /// Set tag of an output stream given as ffmpeg stream specifier, can be used multiple times
+    /// (like --set-stream-meta a:0:language=eng)
+    #[arg(long, value_name = "STREAM:KEY=VALUE", value_parser = parse_stream_tag)]
+    pub set_stream_meta: Vec<(String, String, String)>,
+
+    /// Do not copy any tags from the source, tags given with --set-meta are still written
+    #[arg(long)]
+    pub strip_meta: bool,
+
+    /// Cut exactly even if aligning to keyframes is enabled in the config
+    #[arg(long, conflicts_with = "snap")]
+    pub no_align_keyframe: bool,
+
+    /// Settings from the configuration files
+    #[arg(skip)]
+    pub config: Arc<Config>,
+
+    /// Preset chosen with --preset or in the config
+    #[arg(skip)]
+    pub resolved_preset: Option<Preset>,
+
+    /// Cancelled when the user interrupts rcut
+    #[arg(skip)]
+    pub cancel: CancelToken,
+
+    /// Steps collected in dry run
+    #[arg(skip)]
+    pub plan: Plan,
+
+    /// Result of the command for --json
+    #[arg(skip)]
+    pub report: Reporter,
+}
+
+impl GlobalArgs {
+    /// Whether to align to keyframes, `flag` is the option given to the command
+    pub fn align_keyframe(&self, flag: bool) -> bool {
+        flag || self.snap.is_some() || (self.config.align_keyframe == Some(true) && !self.no_align_keyframe)
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum CliCommands {
     // NOTE no command should operate on file in place, always output to a new one
@@ -28,6 +136,24 @@ pub enum CliCommands {
     /// Add together two or more video files of the same type into one
     Concat(ConcatArgs),
 
+    /// Copy all streams into a different container format without transcoding
+    Remux(RemuxArgs),
+
+    /// Apply the same operation to many files in parallel
+    Batch(BatchArgs),
+
+    /// Check A/V sync of a file, reports gaps, overlaps and offset between audio and video
+    Verify(VerifyArgs),
+
+    /// Make animated preview (GIF, WebP or APNG) of a part of video
+    Gif(GifArgs),
+
+    /// Save the exact frame shown at each of the times as image
+    Frame(FrameArgs),
+
+    /// Make evenly spaced thumbnails of a part of video, or a sprite sheet of them
+    Thumbs(ThumbsArgs),
+
     // /// Create overlay video from image
     // Overlay,
 }
@@ -38,7 +164,8 @@ pub struct ExtractArgs {
     #[arg(short, long, default_value_t = false)]
     pub align_keyframe: bool,
 
-    /// Source file
+    /// Source file, can be local HLS playlist (.m3u8) or DASH manifest (.mpd), output into
+    /// another .m3u8 rewrites only the segments at the edges
     pub source: String,
 
     /// Start time of the segment in millis (for detailed format see help)
@@ -49,12 +176,17 @@ pub struct ExtractArgs {
     #[arg(value_parser = parse_time)]
     pub end_time: u64,
 
-    /// File to output to (if not specified default suffix will be added to source name)
+    /// File to output to, may be a template (defaults to "{dir}/{stem}.cutN.{ext}", N is the
+    /// first free number)
     pub output: Option<String>,
 }
 
 #[derive(Args, Debug, Clone, Default)]
 pub struct RemoveArgs {
+    /// Force align time to keyframes (allows cutting without transcoding, but cuts wont be exact)
+    #[arg(short, long, default_value_t = false)]
+    pub align_keyframe: bool,
+
     /// Source file
     pub source: String,
 
@@ -66,7 +198,8 @@ pub struct RemoveArgs {
     #[arg(value_parser = parse_time)]
     pub end_time: u64,
 
-    /// File to output to (if not specified default suffix will be added to source name)
+    /// File to output to, may be a template (defaults to "{dir}/{stem}.cutN.{ext}", N is the
+    /// first free number)
     pub output: Option<String>,
 }
 
@@ -77,13 +210,24 @@ pub struct SplitArgs {
     #[arg(short, long, default_value_t = false)]
     pub align_keyframe: bool,
 
+    /// Write HLS playlist with segments split at keyframes, interval is the target segment
+    /// duration
+    #[arg(long, default_value_t = false)]
+    pub hls: bool,
+
+    /// Type of segments written with --hls
+    #[arg(long, value_enum, default_value_t, requires = "hls")]
+    pub hls_type: HlsSegmentType,
+
     /// File to operate on
     pub source: String,
 
     #[clap(flatten)]
-    group: TimeOrIntervalGroup,
+    pub group: TimeOrIntervalGroup,
 
-    /// File to output to (if not specified default suffix will be added to source name)
+    /// Template for the parts, plain file name gets index of the part added to it (defaults to
+    /// "{dir}/{stem}.part{index}.{ext}"), with --hls it is the playlist (defaults to
+    /// "{dir}/{stem}.m3u8")
     pub output: Option<String>,
 }
 
@@ -92,11 +236,11 @@ pub struct SplitArgs {
 pub struct TimeOrIntervalGroup {
     /// Interval to split the file in millis (for detailed format see help)
     #[arg(short, long, value_parser = parse_time)]
-    pub interval: Option<f64>,
+    pub interval: Option<u64>,
 
     /// Time to split the media file at in millis (for detailed format see help)
     #[arg(short, long, value_parser = parse_time)]
-    pub time: Option<f64>,
+    pub time: Option<u64>,
 }
 
 #[derive(Args, Debug, Clone, Default)]
@@ -108,6 +252,225 @@ pub struct ConcatArgs {
     pub input: Vec<String>,
 }
 
+#[derive(Args, Debug, Clone, Default)]
+pub struct RemuxArgs {
+    /// Source file
+    pub source: String,
+
+    /// File to output to, container is chosen by the extension
+    pub output: String,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct VerifyArgs {
+    /// File to verify
+    pub file: String,
+
+    /// File the verified one was made from, audio should be offset the same as in it
+    #[arg(long)]
+    pub source: Option<String>,
+
+    /// Start of the span of the source the file was made from (for detailed format see help)
+    #[arg(long, value_parser = parse_time, requires = "source")]
+    pub start: Option<u64>,
+
+    /// End of the span of the source the file was made from (for detailed format see help)
+    #[arg(long, value_parser = parse_time, requires = "source")]
+    pub end: Option<u64>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct GifArgs {
+    /// Frames per second of the animation
+    #[arg(long, default_value_t = 10)]
+    pub fps: u32,
+
+    /// Width of the animation, height keeps the aspect ratio
+    #[arg(short, long, default_value_t = 480)]
+    pub width: u32,
+
+    /// Source file
+    pub source: String,
+
+    /// Start time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub start_time: u64,
+
+    /// End time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub end_time: u64,
+
+    /// File to output to, format is chosen by the extension (gif, webp, png or apng), may be a
+    /// template (defaults to "{dir}/{stem}.previewN.gif", N is the first free number)
+    pub output: Option<String>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct ThumbsArgs {
+    /// Number of thumbnails
+    #[arg(short = 'n', long, default_value_t = 10)]
+    pub count: usize,
+
+    /// Width of each thumbnail, height keeps the aspect ratio
+    #[arg(short, long, default_value_t = 320)]
+    pub width: u32,
+
+    /// Tile the thumbnails into one image and write WebVTT thumbnail track next to it
+    #[arg(long, default_value_t = false)]
+    pub sprite: bool,
+
+    /// Number of columns in the sprite sheet
+    #[arg(long, default_value_t = 5, requires = "sprite")]
+    pub columns: usize,
+
+    /// Source file
+    pub source: String,
+
+    /// Start time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub start_time: u64,
+
+    /// End time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub end_time: u64,
+
+    /// Template for the thumbnails, plain file name gets index of the thumbnail added to it
+    /// (defaults to "{dir}/{stem}.thumb{index:03}.jpg"), with --sprite it is the sprite sheet
+    /// (defaults to "{dir}/{stem}.spriteN.jpg", N is the first free number)
+    pub output: Option<String>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct FrameArgs {
+    /// Source file
+    pub source: String,
+
+    /// Times of the frames in millis (for detailed format see help), optionally followed by file
+    /// to output to, which may be a template (defaults to "{dir}/{stem}.{start}.png"), plain file
+    /// name gets index of the frame added to it if there are multiple times
+    #[arg(required = true, num_args = 1..)]
+    pub times: Vec<String>,
+}
+
+impl FrameArgs {
+    /// Separate the times from the output, which is the last argument if it is not a time
+    pub fn times_and_output(&self) -> Result<(Vec<u64>, Option<String>), String> {
+        let (output, times) = match self.times.split_last() {
+            Some((last, rest)) if !rest.is_empty() && parse_time(last).is_err() => (Some(last.clone()), rest),
+            _ => (None, self.times.as_slice()),
+        };
+
+        let times = times.iter()
+            .map(|x| parse_time(x).map_err(|err| format!("Invalid time {:?}: {}", x, err)))
+            .collect::<Result<Vec<u64>, String>>()?;
+
+        Ok((times, output))
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct BatchArgs {
+    /// Number of files processed at once (defaults to number of CPUs)
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Directory to put outputs into (if not specified outputs are put next to the source)
+    #[arg(short, long)]
+    pub output_dir: Option<String>,
+
+    /// Template for output file names, relative to the output directory if one is set
+    #[arg(long)]
+    pub output: Option<String>,
+
+    #[command(subcommand)]
+    pub op: BatchOperation,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum BatchOperation {
+    /// Extract a part of each video into a new file
+    Extract(BatchExtractArgs),
+
+    /// Remove a part of each video and save the rest into a new file
+    Remove(BatchRemoveArgs),
+
+    /// Split each video at specific point, or interval
+    Split(BatchSplitArgs),
+
+    /// Run every step of a project file (TOML list of extract, remove and split steps) on each
+    /// video
+    Project(BatchProjectArgs),
+}
+
+impl Default for BatchOperation {
+    fn default() -> Self {
+        Self::Extract(Default::default())
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct BatchExtractArgs {
+    /// Force align time to keyframes (allows cutting without transcoding, but cuts wont be exact)
+    #[arg(short, long, default_value_t = false)]
+    pub align_keyframe: bool,
+
+    /// Start time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub start_time: u64,
+
+    /// End time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub end_time: u64,
+
+    /// Source files or glob patterns
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct BatchRemoveArgs {
+    /// Force align time to keyframes (allows cutting without transcoding, but cuts wont be exact)
+    #[arg(short, long, default_value_t = false)]
+    pub align_keyframe: bool,
+
+    /// Start time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub start_time: u64,
+
+    /// End time of the segment in millis (for detailed format see help)
+    #[arg(value_parser = parse_time)]
+    pub end_time: u64,
+
+    /// Source files or glob patterns
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct BatchSplitArgs {
+    /// Force the interval or time to align to keyframes (allows splitting without transcoding, but
+    /// splits wont be exact)
+    #[arg(short, long, default_value_t = false)]
+    pub align_keyframe: bool,
+
+    #[clap(flatten)]
+    pub group: TimeOrIntervalGroup,
+
+    /// Source files or glob patterns
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct BatchProjectArgs {
+    /// Project file with the steps
+    pub project: String,
+
+    /// Source files or glob patterns
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+}
+
 // #[derive(Args, Debug, Clone, Default)]
 // pub struct OverlayArgs {
 //     /// Source file
@@ -199,6 +562,19 @@ mod tests {
     }
 
     #[test]
+    fn test_align_keyframe() {
+        let cli = Cli::try_parse_from(["rcut", "--snap", "inward", "extract", "a.mp4", "1s", "2s"]).unwrap();
+        assert!(cli.global.align_keyframe(false));
+
+        let cli = Cli::try_parse_from(["rcut", "--no-align-keyframe", "extract", "a.mp4", "1s", "2s"]).unwrap();
+        assert!(!cli.global.align_keyframe(false));
+        assert!(cli.global.align_keyframe(true));
+
+        assert!(Cli::try_parse_from(["rcut", "--snap", "inward", "--no-align-keyframe", "extract", "a.mp4", "1s", "2s"]).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::redundant_pattern_matching)]
     fn test_time_parsing() {
         // NOTE remember the output is in microseconds!
 
@@ -224,5 +600,16 @@ mod tests {
         assert!(matches!(parse_time("1 "), Err(_)));
         assert!(matches!(parse_time("1 us"), Err(_)));
     }
-}
 
+    #[test]
+    fn test_frame_times_and_output() {
+        let args = |x: &[&str]| FrameArgs { times: x.iter().map(|x| x.to_string()).collect(), ..Default::default() };
+
+        assert_eq!(args(&["1s", "2s", "out.png"]).times_and_output(), Ok((vec![1_000_000, 2_000_000], Some("out.png".into()))));
+        assert_eq!(args(&["1s", "2s"]).times_and_output(), Ok((vec![1_000_000, 2_000_000], None)));
+
+        // single argument is always time
+        assert!(args(&["out.png"]).times_and_output().is_err());
+        assert!(args(&["1s", "x", "out.png"]).times_and_output().is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Configuration files with defaults for the command line options
+//!
+//! User configuration is read from `$XDG_CONFIG_HOME/rcut/config.toml` (`~/.config/rcut/config.toml`)
+//! and project configuration from `rcut.toml` in the current directory or any of its parents,
+//! project settings override user settings and command line options override both
+//!
+//! ```toml
+//! ffmpeg = "/opt/ffmpeg/bin/ffmpeg"
+//! ffprobe = "/opt/ffmpeg/bin/ffprobe"
+//! loglevel = "warning"
+//! keyframe_window = "10s"
+//! align_keyframe = true
+//! tmp_dir = "/mnt/scratch"
+//!
+//! [templates]
+//! cut = "{dir}/{stem}_{start}.{ext}"
+//! ```
+//!
+//! Encoding presets are described in `preset` module
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::preset::Preset;
+
+/// Name of the project configuration file
+pub const PROJECT_CONFIG: &str = "rcut.toml";
+
+/// Templates for output names, see `template` module
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    pub cut: Option<String>,
+    pub split: Option<String>,
+    pub hls: Option<String>,
+    pub animation: Option<String>,
+    pub thumbs: Option<String>,
+    pub sprite: Option<String>,
+    pub frame: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Path to ffmpeg binary
+    pub ffmpeg: Option<String>,
+
+    /// Path to ffprobe binary
+    pub ffprobe: Option<String>,
+
+    /// Log level of ffmpeg and ffprobe
+    pub loglevel: Option<String>,
+
+    /// How far around the cut to look for keyframes, in micros
+    #[serde(deserialize_with = "deserialize_time")]
+    pub keyframe_window: Option<u64>,
+
+    /// Align cuts to keyframes by default
+    pub align_keyframe: Option<bool>,
+
+    /// Directory for temporary files, relative to the configuration file
+    pub tmp_dir: Option<String>,
+
+    pub templates: Templates,
+
+    /// Name of the preset used when transcoding
+    pub preset: Option<String>,
+
+    /// User defined encoding presets
+    pub presets: BTreeMap<String, Preset>,
+}
+
+/// Parse time in any of the formats accepted on the command line
+pub fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|x| crate::cli::parse_time(&x).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl Config {
+    pub fn ffmpeg(&self) -> &str {
+        self.ffmpeg.as_deref().unwrap_or("ffmpeg")
+    }
+
+    pub fn ffprobe(&self) -> &str {
+        self.ffprobe.as_deref().unwrap_or("ffprobe")
+    }
+
+    pub fn loglevel(&self) -> &str {
+        self.loglevel.as_deref().unwrap_or("error")
+    }
+
+    pub fn keyframe_window(&self) -> u64 {
+        self.keyframe_window.unwrap_or(5_000_000)
+    }
+
+    /// Parse configuration, relative paths are resolved from dir
+    pub fn parse(content: &str, dir: &Path) -> Result<Self, String> {
+        let mut config: Self = toml::from_str(content).map_err(|err| err.to_string())?;
+
+        config.tmp_dir = config.tmp_dir.map(|x| dir.join(x).to_string_lossy().to_string());
+
+        Ok(config)
+    }
+
+    /// Read configuration from path, missing file is the same as empty one
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(format!("Could not read config {:?}: {}", path, err)),
+        };
+
+        Self::parse(&content, path.parent().unwrap_or(Path::new("")))
+            .map_err(|err| format!("Invalid config {:?}: {}", path, err))
+    }
+
+    /// Settings from other override the ones set in self
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            ffmpeg: other.ffmpeg.or(self.ffmpeg),
+            ffprobe: other.ffprobe.or(self.ffprobe),
+            loglevel: other.loglevel.or(self.loglevel),
+            keyframe_window: other.keyframe_window.or(self.keyframe_window),
+            align_keyframe: other.align_keyframe.or(self.align_keyframe),
+            tmp_dir: other.tmp_dir.or(self.tmp_dir),
+            templates: Templates {
+                cut: other.templates.cut.or(self.templates.cut),
+                split: other.templates.split.or(self.templates.split),
+                hls: other.templates.hls.or(self.templates.hls),
+                animation: other.templates.animation.or(self.templates.animation),
+                thumbs: other.templates.thumbs.or(self.templates.thumbs),
+                sprite: other.templates.sprite.or(self.templates.sprite),
+                frame: other.templates.frame.or(self.templates.frame),
+            },
+            preset: other.preset.or(self.preset),
+            presets: self.presets.into_iter().chain(other.presets).collect(),
+        }
+    }
+
+    /// Path of the user configuration
+    fn user_path() -> Option<PathBuf> {
+        let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(x) if !x.is_empty() => PathBuf::from(x),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(dir.join("rcut").join("config.toml"))
+    }
+
+    /// Path of the closest project configuration
+    fn project_path() -> Option<PathBuf> {
+        let cwd = std::env::current_dir().ok()?;
+
+        cwd.ancestors()
+            .map(|x| x.join(PROJECT_CONFIG))
+            .find(|x| x.is_file())
+    }
+
+    /// Load user and project configuration layered on top of each other
+    pub fn load() -> Result<Self, String> {
+        let mut config = Self::default();
+
+        for path in [Self::user_path(), Self::project_path()].into_iter().flatten() {
+            config = config.merge(Self::read(&path)?);
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(r#"
+            ffmpeg = "/opt/ffmpeg"
+            keyframe_window = "10s"
+            tmp_dir = "tmp"
+
+            [templates]
+            cut = "{stem}.x.{ext}"
+        "#, Path::new("/project")).unwrap();
+
+        assert_eq!(config.ffmpeg(), "/opt/ffmpeg");
+        assert_eq!(config.ffprobe(), "ffprobe");
+        assert_eq!(config.keyframe_window(), 10_000_000);
+        assert_eq!(config.tmp_dir.as_deref(), Some("/project/tmp"));
+        assert_eq!(config.templates.cut.as_deref(), Some("{stem}.x.{ext}"));
+
+        assert!(Config::parse("unknown = 1", Path::new("")).is_err());
+        assert!(Config::parse("keyframe_window = \"x\"", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let user = Config::parse("loglevel = \"info\"\nalign_keyframe = true\n[templates]\ncut = \"a\"\nsplit = \"b\"", Path::new("")).unwrap();
+        let project = Config::parse("align_keyframe = false\n[templates]\ncut = \"c\"\n[presets.x]\nencoder = \"libx264\"", Path::new("")).unwrap();
+
+        let config = user.merge(project);
+        assert_eq!(config.loglevel(), "info");
+        assert_eq!(config.align_keyframe, Some(false));
+        assert_eq!(config.templates.cut.as_deref(), Some("c"));
+        assert_eq!(config.templates.split.as_deref(), Some("b"));
+        assert_eq!(config.presets["x"].encoder, "libx264");
+    }
+}
@@ -1,4 +1,12 @@
+mod batch;
 mod cli;
+mod config;
+mod plan;
+mod preset;
+mod progress;
+mod project;
+mod report;
+mod template;
 mod util;
 mod video;
 
@@ -9,19 +17,78 @@ use clap::Parser;
 pub type ExitResult = Result<(), u8>;
 
 fn main() -> ExitCode {
-    let cli_args = cli::Cli::parse();
+    let mut cli_args = cli::Cli::parse();
+    cli_args.global.report = report::Reporter::new(cli_args.global.json);
+
+    // command line options override the configuration
+    match config::Config::load() {
+        Ok(config) => {
+            if cli_args.global.tmp_dir.is_none() {
+                cli_args.global.tmp_dir = config.tmp_dir.clone();
+            }
+
+            if let Some(name) = cli_args.global.preset.as_ref().or(config.preset.as_ref()) {
+                match preset::Preset::resolve(name, &config.presets) {
+                    Ok(x) => cli_args.global.resolved_preset = Some(x),
+                    Err(err) => {
+                        cli_args.global.report.error(err);
+                        return finish(&cli_args.global, Err(1));
+                    },
+                }
+            }
+
+            cli_args.global.config = std::sync::Arc::new(config);
+        },
+        Err(err) => {
+            cli_args.global.report.error(err);
+            return finish(&cli_args.global, Err(1));
+        },
+    }
+
+    // stop running operations on SIGINT / SIGTERM so temporary files can be cleaned up
+    {
+        let cancel = cli_args.global.cancel.clone();
+        ctrlc::set_handler(move || {
+            // second signal means the user does not want to wait for the cleanup
+            if cancel.is_cancelled() {
+                std::process::exit(util::EXIT_CANCELLED.into());
+            }
+
+            eprintln!("Interrupted, stopping..");
+            cancel.cancel();
+        }).expect("Error setting signal handler");
+    }
 
     // TODO check if ffprobe and ffmpeg are available in PATH
 
     use cli::CliCommands;
     let result: ExitResult = match cli_args.cmd {
-        CliCommands::Extract(x) => extract_video_cmd(cli_args.dry_run, x),
-        _ => {
-            dbg!(&cli_args);
-            Ok(())
-        },
+        CliCommands::Extract(x) => extract_video_cmd(&cli_args.global, x),
+        CliCommands::Remove(x) => remove_video_cmd(&cli_args.global, x),
+        CliCommands::Split(x) => split_video_cmd(&cli_args.global, x),
+        CliCommands::Concat(x) => open_video(&cli_args.global, &x.input[0]).concat(&x.input[1..], &x.output),
+        CliCommands::Remux(x) => open_video(&cli_args.global, &x.source).remux(&x.output),
+        CliCommands::Batch(x) => batch::batch_cmd(&cli_args.global, x),
+        CliCommands::Verify(x) => verify_cmd(&cli_args.global, x),
+        CliCommands::Gif(x) => gif_cmd(&cli_args.global, x),
+        CliCommands::Frame(x) => frame_cmd(&cli_args.global, x),
+        CliCommands::Thumbs(x) => thumbs_cmd(&cli_args.global, x),
     };
 
+    finish(&cli_args.global, result)
+}
+
+/// Print the result or the plan of the command and convert it to `ExitCode`
+fn finish(global: &cli::GlobalArgs, result: ExitResult) -> ExitCode {
+    // plan is printed even if it failed part way so it is clear how far it got
+    if global.json {
+        let plan = global.dry_run.then(|| global.plan.steps());
+        let report = global.report.finish(result, plan);
+        println!("{}", serde_json::to_string_pretty(&report).expect("Error serializing report"));
+    } else if global.dry_run {
+        print!("{}", global.plan.render(global.plan_format));
+    }
+
     // convert u8 to ExitCode
     match result {
         Ok(_) => ExitCode::SUCCESS,
@@ -29,14 +96,271 @@ fn main() -> ExitCode {
     }
 }
 
-fn extract_video_cmd(dry_run: bool, args: cli::ExtractArgs) -> ExitResult {
-    let vfile = video::VideoFile {
-        path: PathBuf::from(args.source),
-        dry_run,
+/// Create `VideoFile` with the global options applied
+fn open_video(global: &cli::GlobalArgs, source: &str) -> video::VideoFile {
+    video::VideoFile {
+        path: PathBuf::from(source),
+        dry_run: global.dry_run,
+        progress: global.progress,
+        cancel: global.cancel.clone(),
+        force: global.force,
+        tmp_dir: global.tmp_dir.as_ref().map(PathBuf::from),
+        config: global.config.clone(),
+        preset: global.resolved_preset.clone(),
+        verify: global.verify,
+        validate: global.validate,
+        plan: global.plan.clone(),
+        report: global.report.clone(),
+        warn_extra: global.warn_extra,
+        snap: global.snap.unwrap_or_default(),
+        keep_timestamps: global.keep_timestamps,
+        metadata: video::MetadataOptions {
+            strip: global.strip_meta,
+            set: global.set_meta.clone(),
+            streams: global.set_stream_meta.clone(),
+        },
+    }
+}
+
+/// Render output template printing any errors
+fn render_output(global: &cli::GlobalArgs, template: &str, ctx: &template::Context) -> Result<String, u8> {
+    template::render(template, ctx).map_err(|err| {
+        global.report.error(err);
+        1
+    })
+}
+
+fn extract_video_cmd(global: &cli::GlobalArgs, args: cli::ExtractArgs) -> ExitResult {
+    let vfile = open_video(global, &args.source);
+
+    // output of a manifest has extension of its segments
+    let source = if video::is_manifest(&vfile.path) {
+        let manifest = video::Manifest::load(&vfile.path).map_err(|err| {
+            global.report.error(err);
+            1
+        })?;
+
+        vfile.path.with_extension(manifest.extension())
+    } else {
+        vfile.path.clone()
     };
 
-    // TODO add cut0 .. cut99 so you can just cut without naming them yourself
-    let dest = args.output.unwrap_or_else(|| vfile.new_with_suffix("cut"));
+    let template = match args.output {
+        Some(x) if template::is_template(&x) => x,
+        Some(x) => template::escape(&x),
+        None => global.config.templates.cut.clone().unwrap_or(template::DEFAULT_CUT_TEMPLATE.to_string()),
+    };
+
+    let dest = render_output(global, &template,
+        &template::Context {
+            source: &source,
+            start: Some(args.start_time),
+            end: Some(args.end_time),
+            label: global.label.as_deref(),
+            ..Default::default()
+        },
+    )?;
+
+    if video::is_manifest(&vfile.path) {
+        vfile.extract_from_manifest((args.start_time, args.end_time), global.align_keyframe(args.align_keyframe), &dest)
+    } else {
+        vfile.extract_segment((args.start_time, args.end_time), global.align_keyframe(args.align_keyframe), &dest).map(|_| ())
+    }
+}
+
+fn remove_video_cmd(global: &cli::GlobalArgs, args: cli::RemoveArgs) -> ExitResult {
+    let vfile = open_video(global, &args.source);
+
+    let template = match args.output {
+        Some(x) if template::is_template(&x) => x,
+        Some(x) => template::escape(&x),
+        None => global.config.templates.cut.clone().unwrap_or(template::DEFAULT_CUT_TEMPLATE.to_string()),
+    };
+
+    let dest = render_output(global, &template,
+        &template::Context {
+            source: &vfile.path,
+            start: Some(args.start_time),
+            end: Some(args.end_time),
+            label: global.label.as_deref(),
+            ..Default::default()
+        },
+    )?;
+
+    vfile.remove_segment((args.start_time, args.end_time), global.align_keyframe(args.align_keyframe), &dest)
+}
+
+fn split_video_cmd(global: &cli::GlobalArgs, args: cli::SplitArgs) -> ExitResult {
+    let vfile = open_video(global, &args.source);
+
+    if args.hls {
+        let template = match args.output {
+            Some(x) if template::is_template(&x) => x,
+            Some(x) => template::escape(&x),
+            None => global.config.templates.hls.clone().unwrap_or(template::DEFAULT_HLS_TEMPLATE.to_string()),
+        };
+
+        let playlist = render_output(global, &template,
+            &template::Context {
+                source: &vfile.path,
+                label: global.label.as_deref(),
+                ..Default::default()
+            },
+        )?;
+
+        let Some(target) = args.group.interval else {
+            global.report.error("HLS output requires --interval to be used as the segment duration");
+            return Err(1);
+        };
+
+        return vfile.split_hls(target, args.hls_type, &playlist);
+    }
+
+    let template = match args.output {
+        Some(x) if template::is_template(&x) => x,
+        // plain path gets the index of each part added to it
+        Some(x) => util::path_with_suffix(&PathBuf::from(template::escape(&x)), "part{index}"),
+        None => global.config.templates.split.clone().unwrap_or(template::DEFAULT_SPLIT_TEMPLATE.to_string()),
+    };
+
+    let split_points = match (args.group.time, args.group.interval) {
+        (Some(time), _) => vec![time],
+        (_, Some(interval)) => vfile.interval_split_points(interval)?,
+        (None, None) => unreachable!("clap requires either time or interval"),
+    };
+
+    vfile.split(
+        &split_points,
+        global.align_keyframe(args.align_keyframe),
+        |index, span| render_output(global, &template, &template::Context {
+            source: &vfile.path,
+            start: Some(span.0),
+            end: Some(span.1),
+            index: Some(index),
+            label: global.label.as_deref(),
+        }),
+    )
+}
+
+fn verify_cmd(global: &cli::GlobalArgs, args: cli::VerifyArgs) -> ExitResult {
+    let vfile = open_video(global, &args.file);
+
+    let (expected_length, expected_offset) = match &args.source {
+        Some(source) => {
+            let source = open_video(global, source);
+            let print = |x: (String, u8)| global.report.fail(x);
+
+            let end = match args.end {
+                Some(x) => x,
+                None => source.get_duration().map_err(print)?,
+            };
+
+            // a cut starts all streams together, whole file keeps the offset of the source
+            let offset = match args.start {
+                Some(_) => 0,
+                None => source.get_sync_report().map_err(print)?.av_offset.unwrap_or(0),
+            };
+
+            (Some(end.saturating_sub(args.start.unwrap_or(0))), offset)
+        },
+        None => (None, 0),
+    };
+
+    vfile.verify_sync(expected_length, expected_offset)
+}
+
+fn gif_cmd(global: &cli::GlobalArgs, args: cli::GifArgs) -> ExitResult {
+    let vfile = open_video(global, &args.source);
+
+    let template = match args.output {
+        Some(x) if template::is_template(&x) => x,
+        Some(x) => template::escape(&x),
+        None => global.config.templates.animation.clone().unwrap_or(template::DEFAULT_ANIMATION_TEMPLATE.to_string()),
+    };
+
+    let dest = render_output(global, &template,
+        &template::Context {
+            source: &vfile.path,
+            start: Some(args.start_time),
+            end: Some(args.end_time),
+            label: global.label.as_deref(),
+            ..Default::default()
+        },
+    )?;
+
+    vfile.animation((args.start_time, args.end_time), args.fps, args.width, &dest)
+}
+
+fn frame_cmd(global: &cli::GlobalArgs, args: cli::FrameArgs) -> ExitResult {
+    let vfile = open_video(global, &args.source);
+
+    let (times, output) = args.times_and_output().map_err(|err| {
+        global.report.error(err);
+        1
+    })?;
+
+    let template = match output {
+        Some(x) if template::is_template(&x) => x,
+        // plain path gets the index of each frame added to it, unless there is just one
+        Some(x) if times.len() == 1 => template::escape(&x),
+        Some(x) => util::path_with_suffix(&PathBuf::from(template::escape(&x)), "{index}"),
+        None => global.config.templates.frame.clone().unwrap_or(template::DEFAULT_FRAME_TEMPLATE.to_string()),
+    };
+
+    let dests = times.iter().enumerate()
+        .map(|(index, time)| render_output(global, &template, &template::Context {
+            source: &vfile.path,
+            start: Some(*time),
+            end: Some(*time),
+            index: Some(index),
+            label: global.label.as_deref(),
+        }))
+        .collect::<Result<Vec<String>, u8>>()?;
+
+    vfile.grab_frames(&times, &dests)
+}
+
+fn thumbs_cmd(global: &cli::GlobalArgs, args: cli::ThumbsArgs) -> ExitResult {
+    let vfile = open_video(global, &args.source);
+    let region = (args.start_time, args.end_time);
+
+    if args.sprite {
+        let template = match args.output {
+            Some(x) if template::is_template(&x) => x,
+            Some(x) => template::escape(&x),
+            None => global.config.templates.sprite.clone().unwrap_or(template::DEFAULT_SPRITE_TEMPLATE.to_string()),
+        };
+
+        let dest = render_output(global, &template,
+            &template::Context {
+                source: &vfile.path,
+                start: Some(args.start_time),
+                end: Some(args.end_time),
+                label: global.label.as_deref(),
+                ..Default::default()
+            },
+        )?;
+
+        return vfile.sprite_sheet(region, args.count, args.columns, args.width, &dest);
+    }
+
+    let template = match args.output {
+        Some(x) if template::is_template(&x) => x,
+        // plain path gets the index of each thumbnail added to it
+        Some(x) => util::path_with_suffix(&PathBuf::from(template::escape(&x)), "{index:03}"),
+        None => global.config.templates.thumbs.clone().unwrap_or(template::DEFAULT_THUMB_TEMPLATE.to_string()),
+    };
 
-    vfile.extract_segment((args.start_time, args.end_time), args.align_keyframe, &dest)
+    vfile.thumbnails(
+        region,
+        args.count,
+        args.width,
+        |index, time| render_output(global, &template, &template::Context {
+            source: &vfile.path,
+            start: Some(time),
+            end: Some(time),
+            index: Some(index),
+            label: global.label.as_deref(),
+        }),
+    )
 }
@@ -0,0 +1,297 @@
+//! Execution plan collected in dry run instead of running anything
+//!
+//! ffprobe still runs in dry run as the plan depends on keyframes and durations of the inputs,
+//! those runs are listed in the plan as already done
+
+use std::{process::Command, sync::{Arc, Mutex}};
+
+use serde::Serialize;
+
+/// How the plan is printed at the end of dry run
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// Human readable list of steps
+    #[default]
+    Text,
+
+    /// Shell script that runs the steps
+    Shell,
+
+    /// JSON array of steps
+    Json,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Step {
+    /// ffprobe that was already run to make the plan
+    Probe {
+        command: Vec<String>,
+    },
+
+    Ffmpeg {
+        command: Vec<String>,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+
+        /// Video is stream copied
+        copy: bool,
+
+        /// Name of the preset used for encoding
+        preset: Option<String>,
+
+        /// Length of the re-encoded video in micros
+        encoded: u64,
+
+        /// Estimated size of the output in bytes, from the bitrate of the source
+        estimated_size: Option<u64>,
+    },
+
+    WriteFile {
+        path: String,
+        content: String,
+    },
+
+    CopyFile {
+        from: String,
+        to: String,
+    },
+
+    /// Files joined byte by byte in order
+    JoinFiles {
+        from: Vec<String>,
+        to: String,
+    },
+
+    /// Temp file or directory that is not needed anymore
+    RemoveFile {
+        path: String,
+    },
+}
+
+impl Step {
+    pub fn probe(cmd: &Command) -> Self {
+        Self::Probe { command: command_line(cmd) }
+    }
+
+    /// ffmpeg step, inputs and whether the video is copied are taken from the arguments
+    pub fn ffmpeg(cmd: &Command, outputs: Vec<String>, length: u64, preset: Option<String>, estimated_size: Option<u64>) -> Self {
+        let command = command_line(cmd);
+
+        let inputs = command.windows(2)
+            .filter(|x| x[0] == "-i")
+            .map(|x| x[1].clone())
+            .collect();
+
+        let copy = copies_video(cmd);
+
+        Self::Ffmpeg {
+            command,
+            inputs,
+            outputs,
+            copy,
+            preset: preset.filter(|_| !copy),
+            encoded: if copy { 0 } else { length },
+            estimated_size,
+        }
+    }
+}
+
+/// Check if the video is stream copied by the command
+fn copies_video(cmd: &Command) -> bool {
+    let args: Vec<_> = cmd.get_args().collect();
+
+    args.windows(2)
+        .any(|x| matches!(x[0].to_str(), Some("-c" | "-c:v" | "-vcodec")) && x[1] == "copy")
+}
+
+/// Program and arguments of the command
+fn command_line(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|x| x.to_string_lossy().to_string())
+        .collect()
+}
+
+/// Quote the argument for POSIX shell if needed
+pub fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty() && arg.chars().all(|x| x.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(x));
+
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / 1024.0 / 1024.0)
+}
+
+/// Steps collected in dry run, clones share the same list so it can be collected from workers
+#[derive(Debug, Default, Clone)]
+pub struct Plan(Arc<Mutex<Vec<Step>>>);
+
+impl PartialEq for Plan {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Plan {}
+
+impl Plan {
+    pub fn push(&self, step: Step) {
+        self.0.lock().unwrap().push(step);
+    }
+
+    pub fn steps(&self) -> Vec<Step> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn render(&self, format: PlanFormat) -> String {
+        let steps = self.steps();
+
+        match format {
+            PlanFormat::Text => render_text(&steps),
+            PlanFormat::Shell => render_shell(&steps),
+            PlanFormat::Json => serde_json::to_string_pretty(&steps).expect("Error serializing plan"),
+        }
+    }
+}
+
+fn render_text(steps: &[Step]) -> String {
+    let mut encoded = 0;
+    let mut size = 0;
+    let mut output = String::new();
+    let mut number = 0;
+
+    for step in steps {
+        match step {
+            Step::Probe { command } => {
+                output += &format!("   (probe, already ran) {}\n", command.join(" "));
+                continue;
+            },
+            Step::Ffmpeg { command, inputs, outputs, copy, preset, encoded: length, estimated_size } => {
+                number += 1;
+                encoded += length;
+                size += estimated_size.unwrap_or(0);
+
+                let mode = match (copy, preset) {
+                    (true, _) => "copy".to_string(),
+                    (false, Some(preset)) => format!("re-encode {:.3}s with preset {:?}", *length as f64 / 1_000_000.0, preset),
+                    (false, None) => format!("re-encode {:.3}s", *length as f64 / 1_000_000.0),
+                };
+
+                output += &format!("{:2}. ffmpeg {}: {} -> {}", number, mode, inputs.join(", "), outputs.join(", "));
+                if let Some(size) = estimated_size {
+                    output += &format!(" (~{})", mib(*size));
+                }
+                output += "\n";
+
+                let command: Vec<String> = command.iter().map(|x| shell_quote(x)).collect();
+                output += &format!("    {}\n", command.join(" "));
+            },
+            Step::WriteFile { path, content } => {
+                number += 1;
+                output += &format!("{:2}. write {}\n", number, path);
+                for line in content.lines() {
+                    output += &format!("    | {}\n", line);
+                }
+            },
+            Step::CopyFile { from, to } => {
+                number += 1;
+                output += &format!("{:2}. copy {} -> {}\n", number, from, to);
+            },
+            Step::JoinFiles { from, to } => {
+                number += 1;
+                output += &format!("{:2}. join {} -> {}\n", number, from.join(", "), to);
+            },
+            Step::RemoveFile { path } => {
+                number += 1;
+                output += &format!("{:2}. remove {}\n", number, path);
+            },
+        }
+    }
+
+    format!(
+        "Plan: {} steps, {:.3}s re-encoded, about {} written\n{}",
+        number, encoded as f64 / 1_000_000.0, mib(size), output,
+    )
+}
+
+fn render_shell(steps: &[Step]) -> String {
+    let mut output = String::from("#!/bin/sh\nset -e\n");
+
+    for step in steps {
+        output += &match step {
+            Step::Probe { command } => format!("# probe, already ran: {}\n", command.join(" ")),
+            Step::Ffmpeg { command, .. } => {
+                let command: Vec<String> = command.iter().map(|x| shell_quote(x)).collect();
+                format!("{}\n", command.join(" "))
+            },
+            Step::WriteFile { path, content } => {
+                format!("cat > {} <<'RCUT_EOF'\n{}\nRCUT_EOF\n", shell_quote(path), content.trim_end_matches('\n'))
+            },
+            Step::CopyFile { from, to } => format!("cp {} {}\n", shell_quote(from), shell_quote(to)),
+            Step::JoinFiles { from, to } => {
+                let from: Vec<String> = from.iter().map(|x| shell_quote(x)).collect();
+                format!("cat {} > {}\n", from.join(" "), shell_quote(to))
+            },
+            Step::RemoveFile { path } => format!("rm -rf {}\n", shell_quote(path)),
+        };
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/videos/a.mp4"), "/videos/a.mp4");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_render_plan() {
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-i", "in put.mp4", "-vcodec", "copy", "-ss", "1us", "out.mp4"]);
+
+        let plan = Plan::default();
+        plan.push(Step::ffmpeg(&cmd, vec!["out.mp4".into()], 2_000_000, Some("fast".into()), Some(1024 * 1024)));
+        plan.push(Step::WriteFile { path: "list.txt".into(), content: "file 'a'\n".into() });
+        plan.push(Step::RemoveFile { path: "list.txt".into() });
+
+        let Step::Ffmpeg { inputs, copy, preset, encoded, .. } = &plan.steps()[0] else {
+            panic!("Expected ffmpeg step");
+        };
+        assert_eq!(inputs, &["in put.mp4"]);
+        assert!(copy);
+        assert_eq!(preset, &None);
+        assert_eq!(*encoded, 0);
+
+        assert_eq!(plan.render(PlanFormat::Shell), "#!/bin/sh
+set -e
+ffmpeg -i 'in put.mp4' -vcodec copy -ss 1us out.mp4
+cat > list.txt <<'RCUT_EOF'
+file 'a'
+RCUT_EOF
+rm -rf list.txt
+");
+
+        assert_eq!(plan.render(PlanFormat::Text), "Plan: 3 steps, 0.000s re-encoded, about 1.0 MiB written
+ 1. ffmpeg copy: in put.mp4 -> out.mp4 (~1.0 MiB)
+    ffmpeg -i 'in put.mp4' -vcodec copy -ss 1us out.mp4
+ 2. write list.txt
+    | file 'a'
+ 3. remove list.txt
+");
+
+        assert!(plan.render(PlanFormat::Json).contains("\"kind\": \"write_file\""));
+    }
+}
@@ -0,0 +1,157 @@
+//! Named encoding presets used when video has to be transcoded
+//!
+//! Presets are either built in or defined in the configuration
+//!
+//! ```toml
+//! preset = "small"
+//!
+//! [presets.small]
+//! encoder = "libx265"
+//! crf = 26
+//! preset = "slow"
+//! pix_fmt = "yuv420p"
+//! audio = "aac"
+//! audio_bitrate = "128k"
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    /// Name the preset was resolved from
+    #[serde(skip)]
+    pub name: String,
+
+    /// Video encoder like `libx264`
+    pub encoder: String,
+
+    /// Constant rate factor
+    pub crf: Option<u32>,
+
+    /// Target video bitrate like `8M`
+    pub bitrate: Option<String>,
+
+    /// Encoder speed preset
+    pub preset: Option<String>,
+
+    pub tune: Option<String>,
+
+    /// Pixel format, software formats only as the output is always encoded on the CPU
+    pub pix_fmt: Option<String>,
+
+    /// Audio encoder, audio is copied if not set or set to `copy`
+    pub audio: Option<String>,
+
+    /// Audio bitrate like `192k`
+    pub audio_bitrate: Option<String>,
+}
+
+impl Preset {
+    fn builtin(name: &str, encoder: &str, crf: u32, speed: &str) -> Self {
+        Self {
+            name: name.into(),
+            encoder: encoder.into(),
+            crf: Some(crf),
+            preset: Some(speed.into()),
+            pix_fmt: Some("yuv420p".into()),
+            ..Default::default()
+        }
+    }
+
+    /// Presets that are always available, configuration can override them
+    pub fn builtins() -> Vec<Self> {
+        vec![
+            Self::builtin("fast", "libx264", 20, "veryfast"),
+            Self::builtin("balanced", "libx264", 18, "medium"),
+            Self::builtin("quality", "libx264", 16, "slow"),
+            Self::builtin("hevc", "libx265", 20, "medium"),
+            Self {
+                // constant quality mode in libvpx needs zero bitrate
+                bitrate: Some("0".into()),
+                ..Self::builtin("vp9", "libvpx-vp9", 31, "good")
+            },
+        ]
+    }
+
+    /// Find preset by name, presets from configuration take precedence over built in ones
+    pub fn resolve(name: &str, configured: &BTreeMap<String, Preset>) -> Result<Self, String> {
+        if let Some(preset) = configured.get(name) {
+            return Ok(Self { name: name.into(), ..preset.clone() });
+        }
+
+        let builtins = Self::builtins();
+        if let Some(preset) = builtins.iter().find(|x| x.name == name) {
+            return Ok(preset.clone());
+        }
+
+        let mut names: Vec<String> = builtins.into_iter().map(|x| x.name).collect();
+        names.extend(configured.keys().cloned());
+        names.sort();
+        names.dedup();
+
+        Err(format!("Unknown preset {:?}, available presets are {}", name, names.join(", ")))
+    }
+
+    /// Arguments for ffmpeg to encode the video
+    pub fn video_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec!["-c:v".into(), self.encoder.clone()];
+
+        let options = [
+            ("-crf", self.crf.map(|x| x.to_string())),
+            ("-b:v", self.bitrate.clone()),
+            ("-preset", self.preset.clone()),
+            ("-tune", self.tune.clone()),
+            ("-pix_fmt", self.pix_fmt.clone()),
+        ];
+
+        for (option, value) in options {
+            if let Some(value) = value {
+                args.extend([option.into(), value]);
+            }
+        }
+
+        args
+    }
+
+    /// Arguments for ffmpeg to encode the audio, `None` if audio should be copied
+    pub fn audio_args(&self) -> Option<Vec<String>> {
+        let encoder = self.audio.as_deref().filter(|x| *x != "copy")?;
+
+        let mut args: Vec<String> = vec!["-c:a".into(), encoder.into()];
+        if let Some(bitrate) = &self.audio_bitrate {
+            args.extend(["-b:a".into(), bitrate.clone()]);
+        }
+
+        Some(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_preset() {
+        let mut configured = BTreeMap::new();
+        configured.insert("fast".to_string(), Preset {
+            encoder: "libx265".into(),
+            audio: Some("aac".into()),
+            audio_bitrate: Some("128k".into()),
+            ..Default::default()
+        });
+
+        let preset = Preset::resolve("fast", &configured).unwrap();
+        assert_eq!(preset.name, "fast");
+        assert_eq!(preset.video_args(), ["-c:v", "libx265"]);
+        assert_eq!(preset.audio_args(), Some(["-c:a", "aac", "-b:a", "128k"].map(String::from).to_vec()));
+
+        let preset = Preset::resolve("quality", &configured).unwrap();
+        assert_eq!(preset.video_args(), ["-c:v", "libx264", "-crf", "16", "-preset", "slow", "-pix_fmt", "yuv420p"]);
+        assert_eq!(preset.audio_args(), None);
+
+        assert!(Preset::resolve("unknown", &configured).is_err());
+    }
+}
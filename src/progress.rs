@@ -0,0 +1,308 @@
+use std::{io::{BufRead, BufReader, IsTerminal, Write}, process::{Command, Stdio}, thread, time::Duration};
+
+use crate::{util::{CancelToken, CommandOutputExt, EXIT_CANCELLED}, ExitResult};
+
+/// How to report progress of long running ffmpeg commands
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Progress bar on stderr (only if stderr is a terminal)
+    #[default]
+    Bar,
+
+    /// One json object per line on stderr, for wrapping rcut in other programs
+    Json,
+
+    /// Do not report progress
+    None,
+}
+
+/// Single progress update from ffmpeg
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Progress {
+    /// Time already written to the output in micros
+    pub out_time: u64,
+
+    /// Processing speed relative to realtime
+    pub speed: Option<f64>,
+
+    /// ffmpeg finished processing
+    pub done: bool,
+}
+
+impl Progress {
+    /// Percentage done, total is the expected output length in micros
+    pub fn percent(&self, total: u64) -> Option<f64> {
+        if total == 0 {
+            return None;
+        }
+
+        Some((self.out_time as f64 / total as f64 * 100.0).min(100.0))
+    }
+
+    /// Estimated time left, total is the expected output length in micros
+    pub fn eta(&self, total: u64) -> Option<Duration> {
+        match self.speed {
+            Some(speed) if speed > 0.0 && total > 0 => {
+                let left = total.saturating_sub(self.out_time) as f64 / 1_000_000.0;
+                Some(Duration::from_secs_f64(left / speed))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Parser for output of `ffmpeg -progress`, which is a series of `key=value` lines where each
+/// block ends with `progress=continue` or `progress=end`
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+    current: Progress,
+}
+
+impl ProgressParser {
+    /// Feed a single line, returns progress when a block is complete
+    pub fn feed(&mut self, line: &str) -> Option<Progress> {
+        let (key, value) = line.trim().split_once('=')?;
+
+        match key {
+            // value is N/A at the start
+            "out_time_us" => if let Ok(x) = value.parse::<i64>() {
+                self.current.out_time = x.max(0) as u64;
+            },
+            "speed" => self.current.speed = value.trim().trim_end_matches('x').parse::<f64>().ok(),
+            "progress" => {
+                self.current.done = value == "end";
+                return Some(self.current.clone());
+            },
+            _ => {},
+        }
+
+        None
+    }
+}
+
+/// Format duration as `00:00:00`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+fn print_bar(progress: &Progress, total: u64) {
+    const WIDTH: usize = 30;
+
+    let speed = progress.speed.map_or("?".to_string(), |x| format!("{:.2}x", x));
+
+    let mut stderr = std::io::stderr().lock();
+    let _ = match progress.percent(total) {
+        Some(percent) => {
+            let filled = (percent / 100.0 * WIDTH as f64) as usize;
+
+            write!(
+                stderr,
+                "\r[{}{}] {:5.1}% {:>6} ETA {}",
+                "#".repeat(filled),
+                "-".repeat(WIDTH - filled),
+                percent,
+                speed,
+                progress.eta(total).map_or("--:--:--".to_string(), format_duration),
+            )
+        },
+        // length is not known so just show how much was done
+        None => write!(
+            stderr,
+            "\r{} {:>6}",
+            format_duration(Duration::from_micros(progress.out_time)),
+            speed,
+        ),
+    };
+
+    if progress.done {
+        let _ = writeln!(stderr);
+    }
+
+    let _ = stderr.flush();
+}
+
+fn print_json(progress: &Progress, total: u64) {
+    let value = serde_json::json!({
+        "event": "progress",
+        "out_time_us": progress.out_time,
+        "total_us": total,
+        "percent": progress.percent(total),
+        "speed": progress.speed,
+        "eta_secs": progress.eta(total).map(|x| x.as_secs_f64()),
+        "done": progress.done,
+    });
+
+    eprintln!("{}", value);
+}
+
+/// Run ffmpeg command reporting the progress, total is expected length of the output in micros
+///
+/// The command is killed if cancel token is triggered, in that case `EXIT_CANCELLED` is returned,
+/// environment and working directory of the command are kept but stdout is used for progress
+pub fn run_ffmpeg(cmd: &Command, total: u64, mode: ProgressMode, cancel: &CancelToken) -> ExitResult {
+    run(cmd, total, mode, cancel, false)?.0
+}
+
+/// Same as `run_ffmpeg` but stderr is returned along with the result instead of being printed,
+/// only cancelling is returned as error right away
+pub fn run_ffmpeg_stderr(cmd: &Command, total: u64, mode: ProgressMode, cancel: &CancelToken) -> Result<(ExitResult, String), u8> {
+    run(cmd, total, mode, cancel, true)
+}
+
+fn run(cmd: &Command, total: u64, mode: ProgressMode, cancel: &CancelToken, capture_stderr: bool) -> Result<(ExitResult, String), u8> {
+    cancel.check()?;
+
+    let mode = match mode {
+        ProgressMode::Bar if !std::io::stderr().is_terminal() => ProgressMode::None,
+        x => x,
+    };
+
+    let mut full_cmd = Command::new(cmd.get_program());
+
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => full_cmd.env(key, value),
+            None => full_cmd.env_remove(key),
+        };
+    }
+
+    if let Some(dir) = cmd.get_current_dir() {
+        full_cmd.current_dir(dir);
+    }
+
+    if mode != ProgressMode::None {
+        // progress options are global so they have to be before any of the files
+        full_cmd.args(["-progress", "pipe:1", "-nostats"]);
+        full_cmd.stdout(Stdio::piped());
+    }
+
+    if capture_stderr {
+        full_cmd.stderr(Stdio::piped());
+    }
+
+    full_cmd.args(cmd.get_args());
+
+    let mut child = full_cmd.spawn().expect("Error executing ffmpeg");
+
+    // ffmpeg blocks once the pipe is full so it has to be read while it runs
+    let stderr = child.stderr.take().map(|mut stderr| thread::spawn(move || {
+        let mut output = String::new();
+        let _ = std::io::Read::read_to_string(&mut stderr, &mut output);
+        output
+    }));
+
+    // read the progress in separate thread so the process can be killed at any time
+    let reader = child.stdout.take().map(|stdout| thread::spawn(move || {
+        let mut parser = ProgressParser::default();
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if let Some(progress) = parser.feed(&line) {
+                match mode {
+                    ProgressMode::Bar => print_bar(&progress, total),
+                    ProgressMode::Json => print_json(&progress, total),
+                    ProgressMode::None => {},
+                }
+            }
+        }
+    }));
+
+    let status = loop {
+        if cancel.is_cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            break Err(EXIT_CANCELLED);
+        }
+
+        match child.try_wait().expect("Error executing ffmpeg") {
+            Some(status) => break status.to_exitcode(),
+            None => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+
+    if let Some(reader) = reader {
+        let _ = reader.join();
+    }
+
+    let stderr = stderr.and_then(|x| x.join().ok()).unwrap_or_default();
+
+    // ffmpeg could've been stopped by the same signal before rcut noticed it
+    cancel.check()?;
+
+    Ok((status, stderr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_parser() {
+        let mut parser = ProgressParser::default();
+
+        assert_eq!(parser.feed("frame=10"), None);
+        assert_eq!(parser.feed("out_time_us=N/A"), None);
+        assert_eq!(parser.feed("speed=N/A"), None);
+        assert_eq!(parser.feed("progress=continue"), Some(Progress { out_time: 0, speed: None, done: false }));
+
+        assert_eq!(parser.feed("out_time_us=2500000"), None);
+        assert_eq!(parser.feed("speed=2.5x"), None);
+        assert_eq!(parser.feed("progress=end"), Some(Progress { out_time: 2_500_000, speed: Some(2.5), done: true }));
+    }
+
+    #[test]
+    fn test_progress_eta() {
+        let progress = Progress { out_time: 2_000_000, speed: Some(2.0), done: false };
+
+        assert_eq!(progress.percent(10_000_000), Some(20.0));
+        assert_eq!(progress.eta(10_000_000), Some(Duration::from_secs(4)));
+
+        // unknown length
+        assert_eq!(progress.percent(0), None);
+        assert_eq!(progress.eta(0), None);
+
+        assert_eq!(format_duration(Duration::from_secs(3725)), "01:02:05");
+    }
+
+    #[test]
+    fn test_run_keeps_env_and_dir() {
+        let dir = std::env::temp_dir();
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "test \"$RCUT_TEST\" = yes && test \"$(pwd)\" = \"$EXPECTED\""]);
+        cmd.env("RCUT_TEST", "yes");
+        cmd.env("EXPECTED", dir.canonicalize().unwrap());
+        cmd.current_dir(&dir);
+
+        assert_eq!(run_ffmpeg(&cmd, 0, ProgressMode::None, &CancelToken::default()), Ok(()));
+    }
+
+    #[test]
+    fn test_run_stderr() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo broken >&2; exit 3"]);
+
+        let (status, stderr) = run_ffmpeg_stderr(&cmd, 0, ProgressMode::None, &CancelToken::default()).unwrap();
+        assert_eq!(status, Err(3));
+        assert_eq!(stderr, "broken\n");
+    }
+
+    #[test]
+    fn test_run_cancelled() {
+        let cancel = CancelToken::default();
+
+        {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                cancel.cancel();
+            });
+        }
+
+        let mut cmd = Command::new("sleep");
+        cmd.arg("10");
+
+        let start = std::time::Instant::now();
+        assert_eq!(run_ffmpeg(&cmd, 0, ProgressMode::None, &cancel), Err(EXIT_CANCELLED));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,139 @@
+//! Project files describe a list of operations that `rcut batch project` applies to each input
+//!
+//! Each step writes its own output made from the source, times use the same formats as the
+//! command line
+//!
+//! ```toml
+//! [[step]]
+//! op = "extract"
+//! start = "1m"
+//! end = "2m30s"
+//! output = "{dir}/{stem}.intro.{ext}"
+//!
+//! [[step]]
+//! op = "remove"
+//! start = "0"
+//! end = "10s"
+//! align_keyframe = true
+//!
+//! [[step]]
+//! op = "split"
+//! interval = "10m"
+//! ```
+
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::config::deserialize_time;
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Project {
+    #[serde(rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Step {
+    Extract(CutStep),
+    Remove(CutStep),
+    Split(SplitStep),
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CutStep {
+    #[serde(deserialize_with = "deserialize_required_time")]
+    pub start: u64,
+
+    #[serde(deserialize_with = "deserialize_required_time")]
+    pub end: u64,
+
+    #[serde(default)]
+    pub align_keyframe: bool,
+
+    /// Output template, overrides the one given to batch
+    pub output: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SplitStep {
+    #[serde(default, deserialize_with = "deserialize_time")]
+    pub time: Option<u64>,
+
+    #[serde(default, deserialize_with = "deserialize_time")]
+    pub interval: Option<u64>,
+
+    #[serde(default)]
+    pub align_keyframe: bool,
+
+    /// Output template, overrides the one given to batch
+    pub output: Option<String>,
+}
+
+/// Same as `deserialize_time` but the time has to be set
+fn deserialize_required_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    deserialize_time(deserializer)?.ok_or_else(|| serde::de::Error::custom("time is required"))
+}
+
+impl Project {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let project: Self = toml::from_str(content).map_err(|err| err.to_string())?;
+
+        if project.steps.is_empty() {
+            return Err("project has no steps".into());
+        }
+
+        for (index, step) in project.steps.iter().enumerate() {
+            if let Step::Split(x) = step {
+                if x.time.is_some() == x.interval.is_some() {
+                    return Err(format!("split step {} needs either time or interval", index + 1));
+                }
+            }
+        }
+
+        Ok(project)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read project {:?}: {}", path, err))?;
+
+        Self::parse(&content).map_err(|err| format!("Invalid project {:?}: {}", path, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_project() {
+        let project = Project::parse(r#"
+            [[step]]
+            op = "extract"
+            start = "1m"
+            end = "2m"
+            output = "{stem}.intro.{ext}"
+
+            [[step]]
+            op = "split"
+            interval = "10s"
+            align_keyframe = true
+        "#).unwrap();
+
+        assert_eq!(project.steps, [
+            Step::Extract(CutStep { start: 60_000_000, end: 120_000_000, align_keyframe: false, output: Some("{stem}.intro.{ext}".into()) }),
+            Step::Split(SplitStep { time: None, interval: Some(10_000_000), align_keyframe: true, output: None }),
+        ]);
+
+        assert!(Project::parse("").is_err());
+        assert!(Project::parse("[[step]]\nop = \"extract\"\nstart = \"1s\"").is_err());
+        assert!(Project::parse("[[step]]\nop = \"remove\"\nstart = \"1s\"\nend = \"2s\"\nunknown = 1").is_err());
+        assert!(Project::parse("[[step]]\nop = \"split\"").is_err());
+        assert!(Project::parse("[[step]]\nop = \"concat\"").is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Result of the command collected for `--json` output
+//!
+//! Times are in micros, same as everywhere else
+
+use std::{fmt::Display, sync::{Arc, Mutex}};
+
+use serde::Serialize;
+
+use crate::{plan::Step, video::{Span, SyncReport}};
+
+/// Part of a source that was cut
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Cut {
+    pub source: String,
+
+    /// Span as requested by the user
+    pub requested: Span,
+
+    /// Span that was actually written, it differs from requested when aligned to keyframes
+    pub actual: Span,
+
+    /// Time added before the requested start by aligning to keyframes, negative if the start
+    /// was moved into the requested span
+    pub extra_start: i64,
+
+    /// Time added after the requested end by aligning to keyframes, negative if the end was moved
+    /// into the requested span
+    pub extra_end: i64,
+
+    /// Length of the re-encoded part
+    pub encoded: u64,
+}
+
+impl Cut {
+    pub fn new(source: &str, requested: Span, actual: Span, encoded: u64) -> Self {
+        Self {
+            source: source.to_string(),
+            requested,
+            actual,
+            extra_start: requested.0 as i64 - actual.0 as i64,
+            extra_end: actual.1 as i64 - requested.1 as i64,
+            encoded,
+        }
+    }
+
+    /// Check if either end was moved by more than threshold
+    pub fn is_moved_over(&self, threshold: u64) -> bool {
+        self.extra_start.unsigned_abs() > threshold || self.extra_end.unsigned_abs() > threshold
+    }
+}
+
+/// A/V sync of a file that was verified
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncCheck {
+    pub path: String,
+
+    #[serde(flatten)]
+    pub report: SyncReport,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub success: bool,
+    pub exit_code: u8,
+
+    /// Files that were written
+    pub outputs: Vec<String>,
+
+    pub cuts: Vec<Cut>,
+
+    /// Total length of cuts that were re-encoded, previews and thumbnails are not counted
+    pub encoded: u64,
+
+    pub sync: Vec<SyncCheck>,
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+
+    /// Steps that would run, only in dry run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan: Option<Vec<Step>>,
+}
+
+/// Collects the report while the command runs, clones share the same report
+///
+/// Warnings and errors are always printed to stderr as well
+#[derive(Debug, Default, Clone)]
+pub struct Reporter {
+    json: bool,
+    report: Arc<Mutex<Report>>,
+}
+
+impl PartialEq for Reporter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.report, &other.report)
+    }
+}
+
+impl Eq for Reporter {}
+
+impl Reporter {
+    pub fn new(json: bool) -> Self {
+        Self { json, ..Default::default() }
+    }
+
+    /// Whether the result is printed as JSON, human readable results should not go to stdout then
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    pub fn warning(&self, message: impl Display) {
+        eprintln!("Warning: {}", message);
+        self.report.lock().unwrap().warnings.push(message.to_string());
+    }
+
+    /// Errors from ffprobe and others end with a newline, it is trimmed
+    pub fn error(&self, message: impl Display) {
+        let message = message.to_string();
+        let message = message.trim_end();
+
+        eprintln!("{}", message);
+        self.report.lock().unwrap().errors.push(message.to_string());
+    }
+
+    /// Report error from a probe and return its exit code, for use in `map_err`
+    pub fn fail(&self, (message, code): (String, u8)) -> u8 {
+        self.error(message);
+        code
+    }
+
+    pub fn output(&self, path: &str) {
+        self.report.lock().unwrap().outputs.push(path.to_string());
+    }
+
+    pub fn cut(&self, cut: Cut) {
+        self.report.lock().unwrap().cuts.push(cut);
+    }
+
+    pub fn encoded(&self, length: u64) {
+        self.report.lock().unwrap().encoded += length;
+    }
+
+    pub fn sync(&self, path: &str, report: SyncReport) {
+        self.report.lock().unwrap().sync.push(SyncCheck { path: path.to_string(), report });
+    }
+
+    /// Final report of the command
+    pub fn finish(&self, result: crate::ExitResult, plan: Option<Vec<Step>>) -> Report {
+        Report {
+            success: result.is_ok(),
+            exit_code: result.err().unwrap_or(0),
+            plan,
+            ..self.report.lock().unwrap().clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let reporter = Reporter::new(true);
+        reporter.clone().output("a.mp4");
+        reporter.cut(Cut::new("in.mp4", (1, 5), (0, 6), 0));
+        assert_eq!(reporter.fail(("No such file\n".into(), 2)), 2);
+
+        let report = reporter.finish(Err(2), None);
+        assert!(!report.success);
+        assert_eq!(report.exit_code, 2);
+        assert_eq!(report.outputs, ["a.mp4"]);
+        assert_eq!(report.errors, ["No such file"]);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["cuts"][0]["actual"], serde_json::json!([0, 6]));
+        assert!(json.get("plan").is_none());
+    }
+
+    #[test]
+    fn test_cut_widening() {
+        let cut = Cut::new("in.mp4", (2_500_000, 7_000_000), (2_000_000, 9_000_000), 0);
+        assert_eq!((cut.extra_start, cut.extra_end), (500_000, 2_000_000));
+        assert!(cut.is_moved_over(1_000_000));
+        assert!(!cut.is_moved_over(2_000_000));
+
+        // snapped inward
+        let cut = Cut::new("in.mp4", (2_500_000, 7_000_000), (4_000_000, 6_000_000), 0);
+        assert_eq!((cut.extra_start, cut.extra_end), (-1_500_000, -1_000_000));
+        assert!(cut.is_moved_over(1_000_000));
+    }
+}
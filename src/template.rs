@@ -0,0 +1,249 @@
+//! Templates for output file names
+//!
+//! Placeholders are written in braces, numeric ones accept zero padded width like `{index:03}`
+//!
+//! - `{dir}` directory of the source
+//! - `{stem}` source file name without extension
+//! - `{ext}` source extension (if empty the dot before it is removed as well)
+//! - `{start}`, `{end}` time of the span as `HH-MM-SS.mmm`
+//! - `{index}` index of the part when splitting
+//! - `{label}` label given with `--label`
+//! - `{date}` current date as `YYYY-MM-DD`
+//! - `{n}` smallest number that does not overwrite an existing file
+//!
+//! Literal braces are written as `{{` and `}}`
+
+use std::{collections::HashSet, path::Path, sync::{LazyLock, Mutex}};
+
+use crate::util;
+
+/// Default template for commands that produce a single file
+pub const DEFAULT_CUT_TEMPLATE: &str = "{dir}/{stem}.cut{n}.{ext}";
+
+/// Default template for parts of a split
+pub const DEFAULT_SPLIT_TEMPLATE: &str = "{dir}/{stem}.part{index}.{ext}";
+
+/// Default template for animated previews
+pub const DEFAULT_ANIMATION_TEMPLATE: &str = "{dir}/{stem}.preview{n}.gif";
+
+/// Default template for single thumbnails
+pub const DEFAULT_THUMB_TEMPLATE: &str = "{dir}/{stem}.thumb{index:03}.jpg";
+
+/// Default template for sprite sheet of thumbnails
+pub const DEFAULT_SPRITE_TEMPLATE: &str = "{dir}/{stem}.sprite{n}.jpg";
+
+/// Default template for frame grabs
+pub const DEFAULT_FRAME_TEMPLATE: &str = "{dir}/{stem}.{start}.png";
+
+/// Default template for HLS playlist
+pub const DEFAULT_HLS_TEMPLATE: &str = "{dir}/{stem}.m3u8";
+
+/// Values available to the template
+#[derive(Debug, Clone)]
+pub struct Context<'a> {
+    pub source: &'a Path,
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub index: Option<usize>,
+    pub label: Option<&'a str>,
+}
+
+impl Default for Context<'_> {
+    fn default() -> Self {
+        Self {
+            source: Path::new(""),
+            start: None,
+            end: None,
+            index: None,
+            label: None,
+        }
+    }
+}
+
+/// Escape braces so the string is used literally in a template
+pub fn escape(value: &str) -> String {
+    value.replace('{', "{{").replace('}', "}}")
+}
+
+/// Names of all placeholders
+const PLACEHOLDERS: &[&str] = &["dir", "stem", "ext", "start", "end", "index", "label", "date", "n"];
+
+/// Check if string contains any placeholders, strings without them are just plain paths even if
+/// they have braces like `clip{1}.mp4`
+pub fn is_template(template: &str) -> bool {
+    template.replace("{{", "").split('{').skip(1).any(|x| {
+        let placeholder = x.split_once('}').map_or("", |(x, _)| x);
+        let name = placeholder.split_once(':').map_or(placeholder, |(name, _)| name);
+
+        PLACEHOLDERS.contains(&name)
+    })
+}
+
+/// Format time in micros so it can be used in a file name
+fn format_time(micros: u64) -> String {
+    let millis = micros / 1000;
+    format!(
+        "{:02}-{:02}-{:02}.{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        millis % 1000,
+    )
+}
+
+/// Current date in UTC as `YYYY-MM-DD`
+fn format_date() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs());
+
+    let (year, month, day) = util::civil_from_days((secs / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Render the template, `n` is the value used for `{n}`
+fn render_with(template: &str, ctx: &Context, n: usize) -> Result<String, String> {
+    let mut output = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                output.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                output.push('}');
+            },
+            '{' => {
+                let placeholder: String = chars.by_ref().take_while(|x| *x != '}').collect();
+                let (name, width) = match placeholder.split_once(':') {
+                    Some((name, width)) => (name, Some(
+                        width.parse::<usize>().map_err(|_| format!("Invalid width in {{{}}}", placeholder))?
+                    )),
+                    None => (placeholder.as_str(), None),
+                };
+
+                let number = |x: usize| format!("{:0width$}", x, width = width.unwrap_or(0));
+                let missing = |what: &str| format!("Placeholder {{{}}} cannot be used here, {} is not known", name, what);
+
+                let value = match name {
+                    "dir" => match ctx.source.parent() {
+                        Some(x) if !x.as_os_str().is_empty() => x.to_string_lossy().to_string(),
+                        _ => ".".to_string(),
+                    },
+                    "stem" => ctx.source.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+                    "ext" => {
+                        let ext = ctx.source.extension().unwrap_or_default().to_string_lossy().to_string();
+                        if ext.is_empty() && output.ends_with('.') {
+                            output.pop();
+                        }
+
+                        ext
+                    },
+                    "start" => format_time(ctx.start.ok_or_else(|| missing("start time"))?),
+                    "end" => format_time(ctx.end.ok_or_else(|| missing("end time"))?),
+                    "index" => number(ctx.index.ok_or_else(|| missing("index"))?),
+                    "label" => ctx.label.ok_or_else(|| "Placeholder {label} used without --label".to_string())?.to_string(),
+                    "date" => format_date(),
+                    "n" => number(n),
+                    _ => return Err(format!("Unknown placeholder {{{}}}", placeholder)),
+                };
+
+                output += &value;
+            },
+            '}' => return Err("Unmatched '}' in template, use '}}' for literal brace".to_string()),
+            x => output.push(x),
+        }
+    }
+
+    Ok(output)
+}
+
+/// Paths already given out for `{n}`, outputs are written long after their name is picked so
+/// workers of a batch would get the same one otherwise
+static RESERVED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// Render template into a path, `{n}` is increased until the path does not exist and was not
+/// given out before
+pub fn render(template: &str, ctx: &Context) -> Result<String, String> {
+    let first = render_with(template, ctx, 0)?;
+
+    // without the placeholder every number would produce the same path
+    if !template.replace("{{", "").contains("{n") {
+        return Ok(first);
+    }
+
+    let mut reserved = RESERVED.lock().unwrap();
+
+    for n in 0..10_000 {
+        let path = render_with(template, ctx, n)?;
+        if !Path::new(&path).exists() && reserved.insert(path.clone()) {
+            return Ok(path);
+        }
+    }
+
+    Err(format!("Could not find unused name for template {:?}", template))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let ctx = Context {
+            source: Path::new("/videos/file.mp4"),
+            start: Some(61_500_000),
+            end: Some(3_723_004_000),
+            index: Some(7),
+            label: Some("intro"),
+        };
+
+        assert_eq!(render_with("{dir}/{stem}.cut{n}.{ext}", &ctx, 3), Ok("/videos/file.cut3.mp4".to_string()));
+        assert_eq!(render_with("{stem}_{start}_{end}.{ext}", &ctx, 0), Ok("file_00-01-01.500_01-02-03.004.mp4".to_string()));
+        assert_eq!(render_with("{label}-{index:03}.mkv", &ctx, 0), Ok("intro-007.mkv".to_string()));
+        assert_eq!(render_with("{{{stem}}}", &ctx, 0), Ok("{file}".to_string()));
+
+        // relative source without extension
+        let ctx = Context { source: Path::new("file"), ..Default::default() };
+        assert_eq!(render_with("{dir}/{stem}.cut.{ext}", &ctx, 0), Ok("./file.cut".to_string()));
+
+        assert!(render_with("{index}", &ctx, 0).is_err());
+        assert!(render_with("{label}", &ctx, 0).is_err());
+        assert!(render_with("{unknown}", &ctx, 0).is_err());
+        assert!(render_with("{n:x}", &ctx, 0).is_err());
+        assert!(render_with("}", &ctx, 0).is_err());
+    }
+
+    #[test]
+    fn test_render_unique() {
+        let dir = std::env::temp_dir().join(format!("rcut-test-template-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file.cut0.mp4"), "").unwrap();
+        std::fs::write(dir.join("file.cut1.mp4"), "").unwrap();
+
+        let source = dir.join("file.mp4");
+        let ctx = Context { source: &source, ..Default::default() };
+
+        let result = render(DEFAULT_CUT_TEMPLATE, &ctx);
+
+        // the first one is not written yet, it is still taken
+        let next = render(DEFAULT_CUT_TEMPLATE, &ctx);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, Ok(dir.join("file.cut2.mp4").to_string_lossy().to_string()));
+        assert_eq!(next, Ok(dir.join("file.cut3.mp4").to_string_lossy().to_string()));
+    }
+
+    #[test]
+    fn test_is_template() {
+        assert!(is_template("{stem}.mp4"));
+        assert!(!is_template("file.mp4"));
+        assert!(!is_template("{{file}}.mp4"));
+        assert!(!is_template(&escape("{file}.mp4")));
+        assert!(!is_template("clip{1}.mp4"));
+        assert!(is_template("{{{index:03}}}.mp4"));
+    }
+}
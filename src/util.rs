@@ -1,9 +1,14 @@
+use std::sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc};
+
 use crate::ExitResult;
 
+/// Exit code used when the operation was interrupted by the user (same as shells use for SIGINT)
+pub const EXIT_CANCELLED: u8 = 130;
+
 #[allow(unused_imports)]
 pub mod command_extensions {
     pub use std::process::Command;
-    pub use super::{CommandExt, CommandOutputExt};
+    pub use super::CommandOutputExt;
 }
 
 /// Simple extension trait to avoid duplicating code, allow easy conversion to `ExitCode`
@@ -31,37 +36,241 @@ impl CommandOutputExt for std::process::Output {
     }
 }
 
-pub trait CommandExt {
-    /// Prints the command in readable and copy-able format
-    fn print_escaped_cmd(&self) -> ExitResult;
-}
+/// Shared flag used to stop running operations, clones share the same flag
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
 
-impl CommandExt for std::process::Command {
-    /// Print the whole command with quotes around each argument
-    fn print_escaped_cmd(&self) -> ExitResult {
-        println!("(CMD) {:?} \\", self.get_program().to_string_lossy());
-        let mut iter = self.get_args();
-        while let Some(arg) = iter.next() {
-            print!("      {:?}", arg.to_string_lossy());
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
 
-            // do not add backslash on the last argument
-            if iter.len() != 0 {
-                print!(" \\");
-            }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 
-            println!();
+    /// Returns `Err(EXIT_CANCELLED)` if cancelled, for use with `?`
+    pub fn check(&self) -> ExitResult {
+        if self.is_cancelled() {
+            Err(EXIT_CANCELLED)
+        } else {
+            Ok(())
         }
+    }
+}
 
-        Ok(())
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
-/// Simple struct to delete a file after it goes out of scope
-pub struct TempFile<'a>(pub &'a str);
+impl Eq for CancelToken {}
+
+/// Copy path and add suffix before the extension (`file.mp4` -> `file.suffix.mp4`)
+pub fn path_with_suffix(path: &std::path::Path, suffix: &str) -> String {
+    match path.extension() {
+        Some(x) => path.with_extension(format!("{}.{}", suffix, x.to_string_lossy())),
+        None => path.with_extension(suffix),
+    }.to_string_lossy().to_string()
+}
+
+/// Path for a temporary file in dir that is unique for each call even across multiple instances of
+/// rcut, name is added to the end so the file is recognizable
+pub fn unique_temp_path(dir: &std::path::Path, name: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+    dir.join(format!("rcut-{}-{}-{}", std::process::id(), id, name)).to_string_lossy().to_string()
+}
+
+/// Check if both paths point to the same file, path `b` does not have to exist
+pub fn same_file(a: &std::path::Path, b: &std::path::Path) -> bool {
+    // canonicalize the parent as the file itself may not exist yet
+    fn resolve(path: &std::path::Path) -> Option<std::path::PathBuf> {
+        path.canonicalize().ok().or_else(|| {
+            let parent = match path.parent() {
+                Some(x) if !x.as_os_str().is_empty() => x,
+                _ => std::path::Path::new("."),
+            };
+
+            Some(parent.canonicalize().ok()?.join(path.file_name()?))
+        })
+    }
 
-impl<'a> Drop for TempFile<'a> {
+    match (resolve(a), resolve(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Convert days since unix epoch into year, month and day
+///
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Convert year, month and day into days since unix epoch, inverse of `civil_from_days`
+///
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+/// Output that is written under a temporary name in the same directory and moved into place only
+/// when `commit` is called, otherwise the temporary file is deleted
+pub struct AtomicOutput {
+    dest: std::path::PathBuf,
+    temp: String,
+    committed: bool,
+}
+
+impl AtomicOutput {
+    pub fn new(dest: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let dest = std::path::PathBuf::from(dest);
+
+        // hidden file with the same extension so ffmpeg picks the same format, unique for each
+        // output as workers of a batch can write the same dest
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let name = format!(".{}", dest.file_name().unwrap_or_default().to_string_lossy());
+        let temp = path_with_suffix(&dest.with_file_name(name), &format!("part{}-{}", std::process::id(), id));
+
+        Self {
+            dest,
+            temp,
+            committed: false,
+        }
+    }
+
+    /// Path that should be written to
+    pub fn path(&self) -> &str {
+        &self.temp
+    }
+
+    /// Move the temporary file to the destination, existing destination is replaced only if
+    /// `overwrite` is set as it could have been created since it was checked
+    pub fn commit(mut self, overwrite: bool) -> Result<(), String> {
+        let result = match overwrite {
+            true => std::fs::rename(&self.temp, &self.dest),
+            // unlike rename hard link never replaces the destination
+            false => std::fs::hard_link(&self.temp, &self.dest).or_else(|err| match err.kind() {
+                std::io::ErrorKind::AlreadyExists => Err(err),
+                // some filesystems have no hard links
+                _ if self.dest.exists() => Err(std::io::ErrorKind::AlreadyExists.into()),
+                _ => std::fs::copy(&self.temp, &self.dest).map(|_| ()),
+            }),
+        };
+
+        match result {
+            Ok(_) => {
+                self.committed = true;
+
+                // linked or copied, the temporary name is not needed anymore
+                if !overwrite {
+                    let _ = std::fs::remove_file(&self.temp);
+                }
+
+                Ok(())
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(format!("Output {:?} was created while it was being written, use --force to overwrite it", self.dest))
+            },
+            Err(err) => Err(format!("Could not move {:?} to {:?}: {}", self.temp, self.dest, err)),
+        }
+    }
+}
+
+impl Drop for AtomicOutput {
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(self.0);
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp);
+        }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_same_file() {
+        let dir = std::env::current_dir().unwrap();
+
+        assert!(same_file(Path::new("Cargo.toml"), &dir.join("Cargo.toml")));
+        assert!(same_file(Path::new("Cargo.toml"), Path::new("./src/../Cargo.toml")));
+
+        // file that does not exist yet
+        assert!(same_file(Path::new("src/new.mp4"), &dir.join("src/new.mp4")));
+
+        assert!(!same_file(Path::new("Cargo.toml"), Path::new("Cargo.lock")));
+    }
+
+    #[test]
+    fn test_atomic_output() {
+        let output = AtomicOutput::new("/videos/file.mp4");
+        assert!(output.path().starts_with(&format!("/videos/.file.part{}-", std::process::id())));
+        assert!(output.path().ends_with(".mp4"));
+
+        // every output gets its own temporary file
+        assert_ne!(output.path(), AtomicOutput::new("/videos/file.mp4").path());
+    }
+
+    #[test]
+    fn test_atomic_output_commit() {
+        let dir = std::env::temp_dir().join(format!("rcut-test-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("out.txt").to_string_lossy().to_string();
+
+        let first = AtomicOutput::new(&dest);
+        let second = AtomicOutput::new(&dest);
+        std::fs::write(first.path(), "first").unwrap();
+        std::fs::write(second.path(), "second").unwrap();
+
+        // dest appears after both were checked, only forced output replaces it
+        assert_eq!(first.commit(false), Ok(()));
+        assert!(second.commit(false).is_err());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "first");
+
+        let third = AtomicOutput::new(&dest);
+        std::fs::write(third.path(), "third").unwrap();
+        assert_eq!(third.commit(true), Ok(()));
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "third");
+
+        // nothing but dest is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+}
@@ -1,44 +1,310 @@
+mod audio;
+mod concat;
 mod cut;
+mod frame;
+mod gop;
+mod hls;
+mod manifest;
+mod metadata;
+mod preview;
+mod remux;
+mod sidedata;
+mod verify;
 
-use std::{path::PathBuf, time::Duration};
+pub use hls::HlsSegmentType;
+pub use manifest::{is_manifest, Manifest};
+pub use metadata::{parse_stream_tag, parse_tag, MetadataOptions};
+pub use verify::SyncReport;
 
-use crate::util::command_extensions::*;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{config::Config, plan::{Plan, Step}, preset::Preset, progress::{self, ProgressMode}, report::Reporter, util::{self, command_extensions::*, CancelToken}};
 
 pub type Span = (u64, u64);
 
-#[derive(Debug, Default, PartialEq, Eq)]
+/// Format time in micros as seconds for messages
+fn secs(micros: i64) -> String {
+    format!("{:.3}s", micros as f64 / 1_000_000.0)
+}
+
+/// How a cut is moved to keyframes when it is aligned to them
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SnapMode {
+    /// Keyframe at or before the start and at or after the end, the whole region is kept
+    #[default]
+    Outward,
+
+    /// Keyframe at or after the start and at or before the end, nothing outside the region is kept
+    Inward,
+
+    /// Keyframe closest to each end
+    Nearest,
+
+    /// Keyframe at or before the start, the end is cut exactly as stream copy can end on any frame
+    StartOnly,
+}
+
+/// Basic information about a stream from ffprobe
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub index: usize,
+
+    /// `video`, `audio`, `subtitle`, `data` or `attachment`
+    pub codec_type: String,
+
+    pub codec_name: String,
+}
+
+/// Temp file or directory that is deleted when dropped, in dry run nothing was written so
+/// removing it is added to the plan instead
+pub struct TempPath<'a> {
+    path: &'a str,
+    plan: Option<Plan>,
+}
+
+impl Drop for TempPath<'_> {
+    fn drop(&mut self) {
+        let path = std::path::Path::new(self.path);
+
+        match &self.plan {
+            Some(plan) => plan.push(Step::RemoveFile { path: self.path.into() }),
+            None if path.is_dir() => { let _ = std::fs::remove_dir_all(path); },
+            None => { let _ = std::fs::remove_file(path); },
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct VideoFile {
     pub path: PathBuf,
     pub dry_run: bool,
+    pub progress: ProgressMode,
+
+    /// Stops any running ffmpeg when cancelled
+    pub cancel: CancelToken,
+
+    /// Allow overwriting existing outputs
+    pub force: bool,
+
+    /// Directory for temporary files, system temp directory is used if not set
+    pub tmp_dir: Option<PathBuf>,
+
+    /// Tool paths and other settings from the configuration files
+    pub config: Arc<Config>,
+
+    /// How to encode video when transcoding, ffmpeg defaults are used if not set
+    pub preset: Option<Preset>,
+
+    /// Verify A/V sync of every output after it is written
+    pub verify: bool,
+
+    /// Check duration of every output and decode it after it is written
+    pub validate: bool,
+
+    /// Steps are collected here in dry run instead of running them
+    pub plan: Plan,
+
+    /// Outputs, warnings and errors for the result of the command
+    pub report: Reporter,
+
+    /// Warn when aligning to keyframes moves either end of a cut by more than this, in micros
+    pub warn_extra: u64,
+
+    /// How cuts and split points are moved to keyframes when aligning
+    pub snap: SnapMode,
+
+    /// Keep timestamps of the source in cut outputs instead of starting them at zero
+    pub keep_timestamps: bool,
+
+    /// Tags written into outputs
+    pub metadata: MetadataOptions,
 }
 
 impl VideoFile {
+    /// Other file with the same options as this one
+    pub fn with_path(&self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            ..self.clone()
+        }
+    }
+
     /// Copy path of current file and add suffix to the file
     ///
     /// Used all over the place to create output file or temporary files
     pub fn new_with_suffix(&self, suffix: &str) -> String {
-        match self.path.extension() {
-            Some(x) => self.path.with_extension(format!("{}.{}", suffix, x.to_string_lossy())),
-            None => self.path.with_extension(suffix),
-        }.to_string_lossy().to_string()
+        util::path_with_suffix(&self.path, suffix)
+    }
+
+    /// Delete temp file or directory at path when the returned value is dropped
+    pub fn temp_path<'a>(&self, path: &'a str) -> TempPath<'a> {
+        TempPath {
+            path,
+            plan: self.dry_run.then(|| self.plan.clone()),
+        }
+    }
+
+    /// Directory where temporary files are created
+    pub fn tmp_dir(&self) -> PathBuf {
+        self.tmp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Create unique path in the temp directory named after the file with suffix added
+    pub fn new_temp_file(&self, suffix: &str) -> String {
+        let mut path = PathBuf::from(self.new_with_suffix(suffix));
+
+        // joined fragmented mp4 segments are a regular mp4 file
+        if path.extension().is_some_and(|x| x == "m4s") {
+            path.set_extension("mp4");
+        }
+
+        util::unique_temp_path(&self.tmp_dir(), &path.file_name().unwrap_or_default().to_string_lossy())
+    }
+
+    /// Estimate size of length micros of this file from its average bitrate
+    fn estimated_size(&self, length: u64) -> Option<u64> {
+        let metadata = self.path.metadata().ok()?;
+        let duration = self.get_duration().ok()?;
+
+        Some((metadata.len() as f64 * (length as f64 / duration.max(1) as f64)) as u64)
+    }
+
+    /// Make sure temp directory has enough free space for intermediate copy of the video that is
+    /// length micros long, the size is estimated from average bitrate of the whole file
+    fn check_temp_space(&self, length: u64) -> crate::ExitResult {
+        // nothing is written in dry run
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let tmp_dir = self.tmp_dir();
+        if !tmp_dir.is_dir() {
+            self.report.error(format!("Temp directory {:?} does not exist", tmp_dir));
+            return Err(1);
+        }
+
+        let (Some(needed), Ok(available)) = (self.estimated_size(length), fs4::available_space(&tmp_dir)) else {
+            // not being able to estimate should not prevent cutting
+            return Ok(());
+        };
+
+        if needed > available {
+            self.report.error(format!(
+                "Not enough free space in temp directory {:?}, about {} MiB is needed but only {} MiB is available",
+                tmp_dir,
+                needed / 1024 / 1024,
+                available / 1024 / 1024,
+            ));
+            return Err(1);
+        }
+
+        Ok(())
+    }
+
+    /// ffmpeg command with the options every run uses
+    fn ffmpeg_cmd(&self) -> Command {
+        let mut cmd = Command::new(self.config.ffmpeg());
+        cmd.args([
+            "-loglevel", self.config.loglevel(),
+
+            // do not read keys from stdin, breaks terminal when multiple run at once
+            "-nostdin",
+
+            // do not ask to overwrite, outputs are checked before ffmpeg runs
+            "-y",
+        ]);
+
+        cmd
+    }
+
+    /// ffprobe command with the options every run uses
+    fn ffprobe_cmd(&self) -> Command {
+        let mut cmd = Command::new(self.config.ffprobe());
+        cmd.args(["-loglevel", self.config.loglevel()]);
+
+        cmd
+    }
+
+    /// Get all streams in the file
+    pub fn get_streams(&self) -> Result<Vec<StreamInfo>, (String, u8)> {
+        let data = self.probe_json(&["-show_entries", "stream=index,codec_type,codec_name"])?;
+
+        let streams = data["streams"].as_array().cloned().unwrap_or_default();
+        Ok(streams.iter().map(|stream| StreamInfo {
+            index: stream["index"].as_u64().unwrap_or(0) as usize,
+            codec_type: stream["codec_type"].as_str().unwrap_or_default().to_string(),
+            codec_name: stream["codec_name"].as_str().unwrap_or_default().to_string(),
+        }).collect())
+    }
+
+    /// Run ffprobe with json output and parse it
+    fn probe_json(&self, args: &[&str]) -> Result<serde_json::Value, (String, u8)> {
+        let mut cmd = self.ffprobe_cmd();
+        cmd.args(args);
+        cmd.args(["-of", "json"]);
+        cmd.arg(&self.path);
+
+        if self.dry_run {
+            self.plan.push(Step::probe(&cmd));
+        }
+
+        let output = cmd
+            .output()
+            .expect("Error executing ffprobe");
+
+        match output.to_exitcode() {
+            Ok(_) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                Ok(serde_json::from_str(&stdout).expect("Error while parsing json from ffprobe"))
+            },
+            Err(x) => Err((String::from_utf8_lossy(&output.stderr).to_string(), x)),
+        }
+    }
+
+    /// Get duration of the whole file in micros
+    pub fn get_duration(&self) -> Result<u64, (String, u8)> {
+        let data = self.probe_json(&["-show_entries", "format=duration"])?;
+
+        // duration is missing for some streams that have no defined end
+        let duration = data["format"]["duration"]
+            .as_str()
+            .and_then(|x| x.parse::<f64>().ok())
+            .ok_or_else(|| (format!("Could not get duration of {:?}", self.path), 1))?;
+
+        Ok(Duration::from_secs_f64(duration).as_micros().try_into().unwrap())
+    }
+
+    /// Get timestamp of the start of the file in micros, it can be negative
+    pub fn get_start_time(&self) -> Result<i64, (String, u8)> {
+        let data = self.probe_json(&["-show_entries", "format=start_time"])?;
+
+        Ok(data["format"]["start_time"]
+            .as_str()
+            .and_then(|x| x.parse::<f64>().ok())
+            .map_or(0, |x| (x * 1_000_000.0).round() as i64))
     }
 
     /// Get keyframes from the file, if region is supplied then limit it to that region
+    ///
+    /// Times are relative to the start of the file, same as what ffmpeg uses for seeking
     pub fn get_keyframes(&self, region: Option<Span>) -> Result<Vec<u64>, (String, u8)> {
         let mut args: Vec<String> = vec![];
 
+        // ffprobe works with the timestamps as they are in the file, which do not have to start at
+        // zero (mpegts for example)
+        let start_time = self.get_start_time()?;
+
         if let Some((start, end)) = region {
             // NOTE ffprobe does not care if the start is negative or end is after EOF
             args.extend([
                 // limit the reading to requested region
-                "-read_intervals".into(), format!("{}us%{}us", start, end),
+                "-read_intervals".into(), format!("{}us%{}us", start as i64 + start_time, end as i64 + start_time),
             ]);
         }
 
         let cmd = {
-            let mut cmd = Command::new("ffprobe");
+            let mut cmd = self.ffprobe_cmd();
             cmd.args([
-                    "-loglevel", "error",
                     // there should always be just one stream
                     "-select_streams", "v:0",
                     // skip non key frames
@@ -54,7 +320,7 @@ impl VideoFile {
             cmd.arg(&self.path);
 
             if self.dry_run {
-                let _ = cmd.print_escaped_cmd();
+                self.plan.push(Step::probe(&cmd));
             }
 
             cmd
@@ -79,10 +345,10 @@ impl VideoFile {
                     // the panic here should not happen unless something truly breaks
                     let time_float: f64 = pts_time.parse::<f64>().expect("Error parsing pts_time from json");
 
-                    // im pretty sure u64 can store all the keyframes i can find in a real video..
-                    let micros: u64 = Duration::from_secs_f64(time_float).as_micros().try_into().unwrap();
+                    // im pretty sure i64 can store all the keyframes i can find in a real video..
+                    let micros: i64 = (time_float * 1_000_000.0).round() as i64;
 
-                    times.push(micros);
+                    times.push((micros - start_time).max(0) as u64);
                 }
 
                 // the times may not be in correct order sometimes
@@ -94,12 +360,34 @@ impl VideoFile {
         }
     }
 
-    fn find_keyframes(keyframes: &Vec<u64>, region: Span) -> Result<Span, (String, u8)> {
+    /// Get presentation times of packets of the stream (ffmpeg stream specifier like `a:0`) in
+    /// the region, relative to the start of the file
+    ///
+    /// Only packets are read so nothing is decoded
+    pub fn get_packet_times(&self, stream: &str, region: Span) -> Result<Vec<u64>, (String, u8)> {
+        let start_time = self.get_start_time()?;
+
+        let data = self.probe_json(&[
+            "-select_streams", stream,
+            "-read_intervals", &format!("{}us%{}us", region.0 as i64 + start_time, region.1 as i64 + start_time),
+            "-show_entries", "packet=pts_time",
+        ])?;
+
+        let mut times: Vec<u64> = data["packets"].as_array().cloned().unwrap_or_default().iter()
+            .filter_map(|x| x["pts_time"].as_str().and_then(|x| x.parse::<f64>().ok()))
+            .map(|x| ((x * 1_000_000.0).round() as i64 - start_time).max(0) as u64)
+            .collect();
+
+        times.sort();
+        Ok(times)
+    }
+
+    fn find_keyframes(keyframes: &[u64], region: Span) -> Result<Span, (String, u8)> {
         // find keyframe that is closes to the start time but not after it
         let start_keyframe: Option<u64> = keyframes.iter()
             .filter(|x| region.0 >= **x)
             .cloned()
-            .last();
+            .next_back();
 
         // find keyframe that is closes to the end time but not before it
         let end_keyframe: Option<u64> = keyframes.iter()
@@ -125,21 +413,196 @@ impl VideoFile {
         }
     }
 
+    /// Keyframe closest to time, either at or before it (`before`), after it (`after`) or on any
+    /// side of it
+    fn snap_point(keyframes: &[u64], time: u64, before: bool, after: bool) -> Option<u64> {
+        keyframes.iter()
+            .filter(|x| (!before || **x <= time) && (!after || **x >= time))
+            .min_by_key(|x| x.abs_diff(time))
+            .cloned()
+    }
+
+    /// Move region to keyframes according to the mode
+    fn snap_keyframes(keyframes: &[u64], region: Span, mode: SnapMode) -> Result<Span, (String, u8)> {
+        let span = match mode {
+            SnapMode::Outward => return Self::find_keyframes(keyframes, region),
+            SnapMode::Inward => (
+                Self::snap_point(keyframes, region.0, false, true),
+                Self::snap_point(keyframes, region.1, true, false),
+            ),
+            SnapMode::Nearest => (
+                Self::snap_point(keyframes, region.0, false, false),
+                Self::snap_point(keyframes, region.1, false, false),
+            ),
+            SnapMode::StartOnly => (Self::snap_point(keyframes, region.0, true, false), Some(region.1)),
+        };
+
+        match span {
+            (Some(start), Some(end)) if start < end => Ok((start, end)),
+            _ => Err((format!("No keyframes to cut at between {}us and {}us", region.0, region.1), 1)),
+        }
+    }
+
+    /// Get keyframes around the region, end of the file is included as it is always a valid cut
+    /// point
+    fn get_keyframes_around(&self, region: Span) -> Result<Vec<u64>, (String, u8)> {
+        // add some time before and after region to make sure any keyframes are cought
+        let window = self.config.keyframe_window();
+        let mut keyframes = self.get_keyframes(Some((
+            region.0.saturating_sub(window),
+            region.1.saturating_add(window)
+        )))?;
+
+        if keyframes.last().is_none_or(|x| *x < region.1) && region.1 >= self.get_duration()? {
+            keyframes.push(region.1);
+        }
+
+        Ok(keyframes)
+    }
+
     /// Find closest keyframes to the region, output will always be equal or larger than region
     pub fn find_closest_keyframes(&self, region: Span) -> Result<Span, (String, u8)> {
-        // add 5 seconds before and after region to make sure any keyframes are cought
-        let keyframes = self.get_keyframes(Some((
-            region.0.saturating_sub(5_000_000),
-            region.1.saturating_add(5_000_000)
-        )))?;
+        Self::find_keyframes(&self.get_keyframes_around(region)?, region)
+    }
+
+    /// Get keyframes around the region where stream copy can start, as (closed GOP keyframes,
+    /// all keyframes)
+    fn get_copy_points_around(&self, region: Span) -> Result<(Vec<u64>, Vec<u64>), (String, u8)> {
+        let keyframes = self.get_keyframes_around(region)?;
+
+        let window = self.config.keyframe_window();
+        let open = self.get_open_gop_keyframes((region.0.saturating_sub(window), region.1.saturating_add(window)))?;
+
+        Ok((gop::without_open_gops(&keyframes, &open), keyframes))
+    }
+
+    /// Move region to keyframes with the snap mode of this file, keyframes of open GOPs are only
+    /// used if there are no others around
+    pub fn snap_to_keyframes(&self, region: Span) -> Result<Span, (String, u8)> {
+        let (closed, keyframes) = self.get_copy_points_around(region)?;
+
+        Self::snap_keyframes(&closed, region, self.snap).or_else(|_| {
+            let span = Self::snap_keyframes(&keyframes, region, self.snap)?;
+
+            self.report.warning(format!(
+                "cutting at open GOP keyframe at {}, the first frames of the output may not decode",
+                secs(span.0 as i64),
+            ));
+
+            Ok(span)
+        })
+    }
+
+    /// Move split point to a keyframe, it is the start of a part so it is never left as it is
+    pub fn snap_split_point(&self, point: u64) -> Result<u64, (String, u8)> {
+        let (closed, keyframes) = self.get_copy_points_around((point, point))?;
+
+        let (before, after) = match self.snap {
+            SnapMode::Outward | SnapMode::StartOnly => (true, false),
+            SnapMode::Inward => (false, true),
+            SnapMode::Nearest => (false, false),
+        };
+
+        if let Some(x) = Self::snap_point(&closed, point, before, after) {
+            return Ok(x);
+        }
+
+        let keyframe = Self::snap_point(&keyframes, point, before, after)
+            .ok_or_else(|| (format!("Could not find keyframe for split point {}us", point), 1))?;
+
+        self.report.warning(format!(
+            "splitting at open GOP keyframe at {}, the first frames of the part may not decode",
+            secs(keyframe as i64),
+        ));
+
+        Ok(keyframe)
+    }
+
+    /// Make sure dest can be written to, it must not be the source and it must not exist unless
+    /// forced
+    fn check_output(&self, dest: &str, sources: &[&std::path::Path]) -> crate::ExitResult {
+        let dest_path = std::path::Path::new(dest);
 
-        Self::find_keyframes(&keyframes, region)
+        if let Some(source) = sources.iter().find(|x| util::same_file(x, dest_path)) {
+            self.report.error(format!("Output {:?} is the same file as the input {:?}, files are never modified in place", dest, source));
+            return Err(1);
+        }
+
+        if dest_path.exists() && !self.force {
+            self.report.error(format!("Output {:?} already exists, use --force to overwrite it", dest));
+            return Err(1);
+        }
+
+        Ok(())
+    }
+
+    /// Move finished output into place, existing dest is replaced only with --force
+    fn commit_output(&self, output: util::AtomicOutput) -> crate::ExitResult {
+        output.commit(self.force).map_err(|err| {
+            self.report.error(err);
+            1
+        })
+    }
+
+    /// Check the output and let `f` write to temporary file that is moved to dest only if it
+    /// succeeds, so dest is never left half-written
+    fn write_output(&self, dest: &str, f: impl FnOnce(&str) -> crate::ExitResult) -> crate::ExitResult {
+        self.check_output(dest, &[&self.path])?;
+
+        if self.dry_run {
+            return f(dest);
+        }
+
+        let output = util::AtomicOutput::new(dest);
+        f(output.path())?;
+        self.commit_output(output)?;
+
+        self.report.output(dest);
+        Ok(())
+    }
+
+    /// Add ffmpeg writing outputs to the plan, length is the length of the outputs in micros
+    fn plan_ffmpeg(&self, cmd: &Command, outputs: &[&str], length: u64) {
+        // preset is only used if its encoder is
+        let preset = self.preset.as_ref()
+            .filter(|x| cmd.get_args().any(|arg| arg == x.encoder.as_str()))
+            .map(|x| x.name.clone());
+
+        let outputs = outputs.iter().map(|x| x.to_string()).collect();
+        let estimated_size = Some(length).filter(|x| *x > 0).and_then(|x| self.estimated_size(x));
+
+        self.plan.push(Step::ffmpeg(cmd, outputs, length, preset, estimated_size));
+    }
+
+    /// Run ffmpeg or just add it to the plan in dry run, length is expected length of the output
+    /// in micros
+    ///
+    /// Partially written dest is removed if the operation is cancelled
+    fn run_ffmpeg(&self, cmd: &Command, dest: &str, length: u64) -> crate::ExitResult {
+        self.run_ffmpeg_to(cmd, &[dest], length)
+    }
+
+    /// Same as `run_ffmpeg` for command that writes all of dests
+    fn run_ffmpeg_to(&self, cmd: &Command, dests: &[&str], length: u64) -> crate::ExitResult {
+        if self.dry_run {
+            self.plan_ffmpeg(cmd, dests, length);
+            return Ok(());
+        }
+
+        let result = progress::run_ffmpeg(cmd, length, self.progress, &self.cancel);
+        if self.cancel.is_cancelled() {
+            for dest in dests {
+                let _ = std::fs::remove_file(dest);
+            }
+        }
+
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::VideoFile;
+    use super::{SnapMode, VideoFile};
 
     /// Test if keyframes are properly searched
     #[test]
@@ -157,6 +620,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_snap_keyframes() {
+        let keyframes = vec![0, 2_000_000, 4_000_000, 6_000_000, 8_000_000];
+        let region = (1_500_000, 6_500_000);
+
+        assert_eq!(VideoFile::snap_keyframes(&keyframes, region, SnapMode::Outward), Ok((0, 8_000_000)));
+        assert_eq!(VideoFile::snap_keyframes(&keyframes, region, SnapMode::Inward), Ok((2_000_000, 6_000_000)));
+        assert_eq!(VideoFile::snap_keyframes(&keyframes, region, SnapMode::Nearest), Ok((2_000_000, 6_000_000)));
+        assert_eq!(VideoFile::snap_keyframes(&keyframes, region, SnapMode::StartOnly), Ok((0, 6_500_000)));
+
+        // no keyframe inside the region
+        assert!(VideoFile::snap_keyframes(&keyframes, (2_500_000, 3_500_000), SnapMode::Inward).is_err());
+
+        assert_eq!(VideoFile::snap_point(&keyframes, 4_500_000, true, false), Some(4_000_000));
+        assert_eq!(VideoFile::snap_point(&keyframes, 4_500_000, false, true), Some(6_000_000));
+        assert_eq!(VideoFile::snap_point(&keyframes, 5_500_000, false, false), Some(6_000_000));
+    }
+
+    /// Test if temp files are put into temp directory with unique names
+    #[test]
+    fn test_new_temp_file() {
+        use std::path::PathBuf;
+
+        let vfile = VideoFile {
+            path: PathBuf::from("/videos/file.mp4"),
+            tmp_dir: Some(PathBuf::from("/tmp/rcut")),
+            ..Default::default()
+        };
+
+        let first = vfile.new_temp_file("temp");
+        let second = vfile.new_temp_file("temp");
+
+        assert!(first.starts_with("/tmp/rcut/rcut-"));
+        assert!(first.ends_with("-file.temp.mp4"));
+        assert_ne!(first, second);
+    }
+
     /// Test if suffix is replaced properly
     #[test]
     fn test_new_with_suffix() {
@@ -0,0 +1,371 @@
+use super::{cut::seek_args, remux::Container, Span, VideoFile};
+use crate::{plan::Step, util};
+
+/// Encoder that produces the same codec, for codecs ffmpeg can encode
+pub fn encoder_for(codec: &str) -> Option<&str> {
+    match codec {
+        "aac" => Some("aac"),
+        "mp3" => Some("libmp3lame"),
+        "opus" => Some("libopus"),
+        "vorbis" => Some("libvorbis"),
+        "ac3" => Some("ac3"),
+        "eac3" => Some("eac3"),
+        "flac" => Some("flac"),
+        "alac" => Some("alac"),
+        "mp2" => Some("mp2"),
+        x if x.starts_with("pcm_") => Some(x),
+        _ => None,
+    }
+}
+
+/// Check if time falls on a start of a packet, times before the first or after the last packet
+/// are the ends of the stream so no trimming is needed there
+pub fn on_packet_boundary(packets: &[u64], time: u64, tolerance: u64) -> bool {
+    match (packets.first(), packets.last()) {
+        (Some(first), Some(last)) if time > *first && time < *last => {
+            packets.iter().any(|x| x.abs_diff(time) <= tolerance)
+        },
+        _ => true,
+    }
+}
+
+/// Audio of a cut split where it does not line up with packets, only head and tail are
+/// re-encoded and the whole packets in the middle are copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioSplice {
+    pub head: Span,
+    pub middle: Span,
+    pub tail: Span,
+
+    /// Audio before the head that is encoded with it, so the encoded packets line up with the
+    /// copied ones, it ends up before the start of the video
+    pub lead: u64,
+}
+
+impl AudioSplice {
+    /// Check if the whole region can be copied
+    pub fn is_copy(&self) -> bool {
+        self.head.0 == self.head.1 && self.tail.0 == self.tail.1
+    }
+}
+
+/// Split region at the first and last whole packet, `None` if there is no whole packet in it
+///
+/// Packets are start times around both ends of the region
+pub fn audio_splice(packets: &[u64], region: Span, tolerance: u64) -> Option<AudioSplice> {
+    let start = match on_packet_boundary(packets, region.0, tolerance) {
+        true => region.0,
+        false => packets.iter().copied().find(|x| *x > region.0)?,
+    };
+
+    // the packet that starts before the end goes past it
+    let end = match on_packet_boundary(packets, region.1, tolerance) {
+        true => region.1,
+        false => packets.iter().copied().filter(|x| *x < region.1).max()?,
+    };
+
+    // head is encoded from the packet it starts in
+    let lead = match start == region.0 {
+        true => 0,
+        false => region.0 - packets.iter().copied().filter(|x| *x <= region.0).max()?,
+    };
+
+    (start < end).then_some(AudioSplice {
+        head: (region.0, start),
+        middle: (start, end),
+        tail: (end, region.1),
+        lead,
+    })
+}
+
+/// Value for `-disposition` from ffprobe `disposition` of a stream
+pub fn disposition_arg(disposition: &serde_json::Value) -> String {
+    let flags: Vec<&str> = disposition.as_object().into_iter().flatten()
+        .filter(|(_, value)| value.as_u64() == Some(1))
+        .map(|(key, _)| key.as_str())
+        .collect();
+
+    match flags.is_empty() {
+        true => "0".into(),
+        false => flags.join("+"),
+    }
+}
+
+/// How audio of a re-encoded cut is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioCut {
+    /// Packets line up with the cut or the codec cannot be encoded
+    Copy,
+
+    /// Whole audio is encoded with the arguments
+    Encode(Vec<String>),
+
+    /// Audio was spliced into separate file that replaces the audio of the cut, it starts lead
+    /// micros before the cut
+    Spliced { lead: u64 },
+}
+
+/// Audio stream that is trimmed with the video
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AudioInfo {
+    pub codec_name: String,
+    pub profile: Option<String>,
+    pub sample_rate: u64,
+
+    /// Bitrate in bits per second, not known for some containers
+    pub bit_rate: Option<u64>,
+
+    /// Value for `-disposition` that keeps flags like default or forced
+    pub disposition: String,
+}
+
+impl VideoFile {
+    /// Get the first audio stream, if there is any
+    pub fn get_audio_info(&self) -> Result<Option<AudioInfo>, (String, u8)> {
+        let data = self.probe_json(&["-select_streams", "a:0", "-show_entries", "stream=codec_name,profile,sample_rate,bit_rate:stream_disposition"])?;
+
+        let stream = &data["streams"][0];
+        let Some(codec_name) = stream["codec_name"].as_str() else {
+            return Ok(None);
+        };
+
+        // ffprobe prints numbers as strings
+        let number = |x: &serde_json::Value| x.as_str().and_then(|x| x.parse::<u64>().ok());
+
+        Ok(Some(AudioInfo {
+            codec_name: codec_name.to_string(),
+            profile: stream["profile"].as_str().map(String::from),
+            sample_rate: number(&stream["sample_rate"]).unwrap_or(48_000),
+            bit_rate: number(&stream["bit_rate"]),
+            disposition: disposition_arg(&stream["disposition"]),
+        }))
+    }
+
+    /// Get start times of audio packets around both ends of the region
+    fn get_audio_packets_around(&self, region: Span) -> Result<Vec<u64>, (String, u8)> {
+        let mut packets: Vec<u64> = vec![];
+
+        for time in [region.0, region.1] {
+            packets.extend(self.get_packet_times("a:0", (time.saturating_sub(1_000_000), time + 1_000_000))?);
+        }
+
+        packets.sort();
+        packets.dedup();
+
+        Ok(packets)
+    }
+
+    /// Decide how audio of region is written when the video is re-encoded, as audio can only be
+    /// copied in whole packets (1024 samples for AAC)
+    ///
+    /// If the ends do not line up with packets then only the packets at the ends are re-encoded
+    /// with the same codec and bitrate as the source has and the audio is written into `spliced`,
+    /// that works only for AAC-LC as the encoder primes it with exactly one packet, other codecs
+    /// are encoded whole
+    pub(super) fn audio_cut(&self, region: Span, spliced: &str) -> Result<AudioCut, u8> {
+        let print = |x: (String, u8)| self.report.fail(x);
+
+        let Some(audio) = self.get_audio_info().map_err(print)? else {
+            return Ok(AudioCut::Copy);
+        };
+
+        // rounding of the times to micros is at most one sample off
+        let tolerance = 1_000_000 / audio.sample_rate.max(1) + 1;
+
+        let packets = self.get_audio_packets_around(region).map_err(print)?;
+        let splice = audio_splice(&packets, region, tolerance);
+
+        if splice.is_some_and(|x| x.is_copy()) {
+            return Ok(AudioCut::Copy);
+        }
+
+        let Some(encoder) = encoder_for(&audio.codec_name) else {
+            self.report.warning(format!("audio is cut on packet boundaries as {} cannot be encoded", audio.codec_name));
+            return Ok(AudioCut::Copy);
+        };
+
+        let mut args: Vec<String> = vec!["-c:a".into(), encoder.into()];
+        if let Some(bit_rate) = audio.bit_rate {
+            args.extend(["-b:a".into(), bit_rate.to_string()]);
+        }
+
+        // the encoder only writes AAC-LC, other profiles cannot be joined with copied packets, and
+        // other encoders prime with part of a packet that cannot be dropped without decoding
+        let joinable = audio.codec_name == "aac" && audio.profile.as_deref().is_none_or(|x| x == "LC");
+
+        match splice {
+            Some(splice) if joinable => {
+                eprintln!("Audio does not align with the cut, re-encoding only its ends");
+                self.splice_audio(&splice, &args, spliced)?;
+
+                Ok(AudioCut::Spliced { lead: splice.lead })
+            },
+            _ => {
+                eprintln!("Audio does not align with the cut, re-encoding it");
+                Ok(AudioCut::Encode(args))
+            },
+        }
+    }
+
+    /// Write the first audio stream of the splice into dest, head and tail are encoded with
+    /// `encoder` and the middle is copied
+    ///
+    /// Encoded parts start with a priming packet that is dropped, the head starts `lead` early so
+    /// it is made of whole packets and the joins have no gaps or overlaps
+    fn splice_audio(&self, splice: &AudioSplice, encoder: &[String], dest: &str) -> crate::ExitResult {
+        let head = (splice.head.0 - splice.lead, splice.head.1);
+        let parts: Vec<(Span, bool)> = [(head, true), (splice.middle, false), (splice.tail, true)].into_iter()
+            .filter(|((start, end), _)| start < end)
+            .collect();
+
+        let files: Vec<String> = (0..parts.len())
+            .map(|i| util::unique_temp_path(&self.tmp_dir(), &format!("audio{}.mka", i)))
+            .collect();
+
+        // make sure the temp files are deleted later
+        let _x: Vec<_> = files.iter().map(|x| self.temp_path(x)).collect();
+
+        let mut list = String::new();
+
+        for ((span, encode), file) in parts.iter().zip(&files) {
+            let mut cmd = self.ffmpeg_cmd();
+            cmd.args(seek_args(*span));
+            cmd.arg("-i").arg(&self.path);
+            cmd.args(["-map", "0:a:0"]);
+
+            match encode {
+                true => cmd.args(encoder),
+                false => cmd.args(["-c:a", "copy"]),
+            };
+
+            cmd.arg(file);
+            self.run_ffmpeg(&cmd, file, span.1 - span.0)?;
+
+            // the list is next to the parts
+            let name = std::path::Path::new(file).file_name().unwrap_or_default().to_string_lossy().to_string();
+            list += &format!("file '{}'\n", name.replace('\'', "'\\''"));
+
+            // priming packet is before zero, padding at the end is past the length
+            if *encode {
+                list += &format!("inpoint 0\noutpoint {}us\n", span.1 - span.0);
+            }
+        }
+
+        let list_file = util::unique_temp_path(&self.tmp_dir(), "audio.txt");
+        let _y = self.temp_path(&list_file);
+
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.args(["-f", "concat", "-safe", "0", "-i", &list_file, "-c", "copy"]);
+        cmd.arg(dest);
+
+        if self.dry_run {
+            self.plan.push(Step::WriteFile { path: list_file.clone(), content: list });
+            return self.run_ffmpeg(&cmd, dest, 0);
+        }
+
+        if let Err(err) = std::fs::write(&list_file, list) {
+            self.report.error(format!("Could not write concat list {:?}: {}", list_file, err));
+            return Err(1);
+        }
+
+        self.run_ffmpeg(&cmd, dest, splice.tail.1 - head.0)
+    }
+
+    /// Write video into dest with its first audio stream replaced by the one in audio that starts
+    /// lead micros before the video, everything is copied
+    ///
+    /// The new audio takes the place of the old one along with its tags and disposition
+    pub(super) fn replace_audio(&self, video: &str, audio: &str, lead: u64, dest: &str, length: u64) -> crate::ExitResult {
+        let print = |x: (String, u8)| self.report.fail(x);
+
+        // in dry run the video is not written yet, it has the same streams as this file
+        let streams = match self.dry_run {
+            true => self.get_streams(),
+            false => self.with_path(video).get_streams(),
+        }.map_err(print)?;
+
+        let disposition = self.get_audio_info().map_err(print)?.map_or("0".into(), |x| x.disposition);
+
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.args(["-i", video]);
+        // players skip the audio before the first frame
+        cmd.args(["-itsoffset", &format!("-{}us", lead), "-i", audio]);
+
+        let first_audio = streams.iter().position(|x| x.codec_type == "audio");
+        for (i, stream) in streams.iter().enumerate() {
+            match Some(i) == first_audio {
+                true => cmd.args(["-map", "1:a:0"]),
+                false => cmd.args(["-map".into(), format!("0:{}", stream.index)]),
+            };
+        }
+
+        cmd.args(["-c", "copy"]);
+        cmd.args(["-map_metadata:s:a:0", "0:s:a:0", "-disposition:a:0", &disposition]);
+
+        // same as remuxing, index goes to the start
+        if matches!(Container::from_path(std::path::Path::new(dest)), Some(Container::Mp4 | Container::Mov)) {
+            cmd.args(["-movflags", "+faststart"]);
+        }
+
+        cmd.arg(dest);
+
+        self.run_ffmpeg(&cmd, dest, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_on_packet_boundary() {
+        // 1024 samples at 48kHz
+        let packets = vec![0, 21_333, 42_667, 64_000, 85_333];
+
+        assert!(on_packet_boundary(&packets, 42_667, 21));
+        assert!(on_packet_boundary(&packets, 42_680, 21));
+        assert!(!on_packet_boundary(&packets, 50_000, 21));
+
+        // outside of the stream
+        assert!(on_packet_boundary(&packets, 100_000, 21));
+        assert!(on_packet_boundary(&[], 50_000, 21));
+    }
+
+    #[test]
+    fn test_audio_splice() {
+        // 1024 samples at 48kHz
+        let packets = vec![0, 21_333, 42_667, 64_000, 85_333, 106_667, 128_000];
+
+        assert_eq!(audio_splice(&packets, (30_000, 100_000), 21), Some(AudioSplice {
+            head: (30_000, 42_667),
+            middle: (42_667, 85_333),
+            tail: (85_333, 100_000),
+            lead: 8_667,
+        }));
+
+        // start on a packet, end after the stream
+        let splice = audio_splice(&packets, (21_340, 500_000), 21).unwrap();
+        assert!(splice.is_copy());
+        assert_eq!(splice.middle, (21_340, 500_000));
+        assert_eq!(splice.lead, 0);
+
+        // no whole packet to copy
+        assert_eq!(audio_splice(&packets, (25_000, 40_000), 21), None);
+    }
+
+    #[test]
+    fn test_disposition_arg() {
+        let disposition = serde_json::json!({ "default": 1, "dub": 0, "forced": 1 });
+        assert_eq!(disposition_arg(&disposition), "default+forced");
+
+        assert_eq!(disposition_arg(&serde_json::json!({ "default": 0 })), "0");
+        assert_eq!(disposition_arg(&serde_json::Value::Null), "0");
+    }
+
+    #[test]
+    fn test_encoder_for() {
+        assert_eq!(encoder_for("aac"), Some("aac"));
+        assert_eq!(encoder_for("pcm_s16le"), Some("pcm_s16le"));
+        assert_eq!(encoder_for("dts"), None);
+    }
+}
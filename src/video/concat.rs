@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use super::VideoFile;
+use crate::{plan::Step, util};
+
+impl VideoFile {
+    /// Join this file and others of the same type together without transcoding using the concat
+    /// demuxer
+    pub fn concat(&self, others: &[String], dest: &str) -> crate::ExitResult {
+        let others_paths: Vec<&Path> = others.iter().map(Path::new).collect();
+        self.check_output(dest, &others_paths)?;
+
+        let inputs: Vec<String> = std::iter::once(self.path.to_string_lossy().to_string())
+            .chain(others.iter().cloned())
+            .collect();
+
+        self.write_output(dest, |dest| self.concat_to(&inputs, dest))?;
+
+        // output is as long as all the inputs together
+        self.verify_output(dest, || {
+            std::iter::once(self.clone())
+                .chain(others.iter().map(|x| self.with_path(x)))
+                .map(|x| x.get_duration().ok())
+                .sum()
+        }, 0)
+    }
+
+    /// List of inputs for the concat demuxer
+    ///
+    /// Concat demuxer resolves relative paths from the list file so they are all made absolute,
+    /// in dry run inputs can be temp files that are not written yet so they are not resolved
+    fn concat_list(&self, inputs: &[String]) -> Result<String, u8> {
+        let mut list = String::new();
+
+        for input in inputs.iter().map(Path::new) {
+            let path = match self.dry_run {
+                true => std::path::absolute(input),
+                false => input.canonicalize(),
+            };
+
+            let path = match path {
+                Ok(x) => x,
+                Err(err) => {
+                    self.report.error(format!("Could not find input file {:?}: {}", input, err));
+                    return Err(1);
+                }
+            };
+
+            list += &format!("file '{}'\n", path.to_string_lossy().replace('\'', "'\\''"));
+        }
+
+        Ok(list)
+    }
+
+    /// Join inputs writing directly into dest without any checks, tags and streams are taken
+    /// from this file
+    pub(super) fn concat_to(&self, inputs: &[String], dest: &str) -> crate::ExitResult {
+        let list = self.concat_list(inputs)?;
+
+        let list_file = util::unique_temp_path(&self.tmp_dir(), "concat.txt");
+        let _x = self.temp_path(&list_file);
+
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.args([
+            "-f", "concat",
+            // allow absolute paths in the list
+            "-safe", "0",
+            "-i", &list_file,
+            "-c", "copy",
+        ]);
+        // concat demuxer makes its own timestamps so there are no original ones to keep
+        cmd.args(self.timestamp_args(dest, false));
+        cmd.args(self.metadata_args(0)?);
+        cmd.args(self.remux_args_for(&self.path.to_string_lossy(), dest, true)?);
+        cmd.arg(dest);
+
+        if self.dry_run {
+            self.plan.push(Step::WriteFile { path: list_file.clone(), content: list });
+            self.plan_ffmpeg(&cmd, &[dest], 0);
+            return Ok(());
+        }
+
+        if let Err(err) = std::fs::write(&list_file, list) {
+            self.report.error(format!("Could not write concat list {:?}: {}", list_file, err));
+            return Err(1);
+        }
+
+        // output is as long as all the inputs together, inputs that cannot be probed are left
+        // out of the progress
+        let length = inputs.iter()
+            .map(|x| self.with_path(x))
+            .filter_map(|x| x.get_duration().ok())
+            .sum();
+
+        self.run_ffmpeg(&cmd, dest, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concat_list_dry_run() {
+        let dir = std::env::temp_dir().join(format!("rcut-test-concat-{}", std::process::id()));
+        let inputs = [dir.join("temp0.mp4"), dir.join("it's.mp4")].map(|x| x.to_string_lossy().to_string());
+
+        // temp files are not written in dry run
+        let vfile = VideoFile { dry_run: true, ..Default::default() };
+        assert_eq!(vfile.concat_list(&inputs), Ok(format!(
+            "file '{}'\nfile '{}'\n",
+            inputs[0], inputs[1].replace('\'', "'\\''"),
+        )));
+
+        let vfile = VideoFile::default();
+        assert_eq!(vfile.concat_list(&inputs), Err(1));
+    }
+}
@@ -1,50 +1,107 @@
-use super::{Span, VideoFile};
-use crate::util::{self, command_extensions::*};
+use std::path::Path;
 
-const COMMON_FFMPEG_ARGS: &[&str] = &[
-    // print only errors
-    "-loglevel", "error",
+use super::{audio::AudioCut, secs, sidedata::VideoProps, Span, VideoFile};
+use crate::{report::Cut, util};
 
-    // do not ask to overwrite
-    "-y",
-
-    // do not re-encode audio
-    "-acodec", "copy",
-];
+/// Input options to read just the span, length is used for the end so it does not depend on how
+/// the timestamps are shifted
+pub(super) fn seek_args(span: Span) -> Vec<String> {
+    vec![
+        "-ss".into(), format!("{}us", span.0),
+        "-t".into(), format!("{}us", span.1.saturating_sub(span.0)),
+    ]
+}
 
 impl VideoFile {
-    pub fn extract_segment(&self, region: Span, force_align_keyframes: bool, dest: &str) -> crate::ExitResult {
+    /// Extract region into dest, returns the span that was actually written (it differs from
+    /// region if aligned to keyframes)
+    pub fn extract_segment(&self, region: Span, force_align_keyframes: bool, dest: &str) -> Result<Span, u8> {
+        let mut written = region;
+        self.write_output(dest, |dest| {
+            written = self.cut_segment(region, force_align_keyframes, dest)?;
+            Ok(())
+        })?;
+
+        self.verify_output(dest, || Some(self.span_length(written)), 0)?;
+
+        Ok(written)
+    }
+
+    /// Report the span that was cut, warn if it is much larger than requested
+    fn report_cut(&self, requested: Span, actual: Span, encoded: u64) {
+        let cut = Cut::new(&self.path.to_string_lossy(), requested, actual, encoded);
+
+        eprintln!(
+            "Cut {} to {} (requested {} to {}, {} extra at the start, {} at the end)",
+            secs(actual.0 as i64), secs(actual.1 as i64),
+            secs(requested.0 as i64), secs(requested.1 as i64),
+            secs(cut.extra_start), secs(cut.extra_end),
+        );
+
+        if cut.is_moved_over(self.warn_extra) {
+            self.report.warning(format!(
+                "aligning to keyframes added {} at the start and {} at the end of the requested span, more than {}",
+                secs(cut.extra_start), secs(cut.extra_end), secs(self.warn_extra as i64),
+            ));
+        }
+
+        self.report.cut(cut);
+    }
+
+    /// Length of span of this file, end of the file may be past the actual duration
+    fn span_length(&self, span: Span) -> u64 {
+        let end = self.get_duration().map_or(span.1, |x| span.1.min(x));
+
+        end.saturating_sub(span.0)
+    }
+
+    /// Extract segment writing directly into dest without any checks, returns the span that was
+    /// actually written (it differs from region if aligned to keyframes)
+    fn cut_segment(&self, region: Span, force_align_keyframes: bool, dest: &str) -> Result<Span, u8> {
         // TODO some files have high compression and there are not many keyframes, find a way to
         // detect that so the user is warned
         let keyframes = match self.find_closest_keyframes(region) {
             Ok(x) => x,
             Err((err, code)) => {
-                eprintln!("{}", err);
+                self.report.error(err);
                 return Err(code);
             }
         };
 
         assert!(keyframes.0 <= keyframes.1, "Start keyframe is after the end keyframe");
 
-        // no transcoding is needed if keyframes align
-        let needs_transcoding = keyframes.0 != region.0 || keyframes.1 != region.1;
+        // no transcoding is needed if keyframes align, unless the start is an open GOP keyframe
+        let needs_transcoding = keyframes.0 != region.0 || keyframes.1 != region.1
+            || !self.is_random_access_point(region.0).map_err(|x| self.report.fail(x))?;
 
-        if force_align_keyframes || !needs_transcoding {
-            println!("Cutting video at keyframes");
-            segment_aligned(&self.path.to_string_lossy(), dest, keyframes, self.dry_run)
+        if !needs_transcoding {
+            eprintln!("Cutting video at keyframes");
+            self.segment_aligned(&self.path.to_string_lossy(), dest, keyframes)?;
+            self.report_cut(region, keyframes, 0);
+
+            Ok(keyframes)
+        } else if force_align_keyframes {
+            let span = self.snap_to_keyframes(region).map_err(|x| self.report.fail(x))?;
+
+            eprintln!("Cutting video at keyframes");
+            self.segment_aligned(&self.path.to_string_lossy(), dest, span)?;
+            self.report_cut(region, span, 0);
+
+            Ok(span)
         } else {
-            // create temp file at same place as dest but with different name
-            let temp_file = self.new_with_suffix("temp");
+            self.check_temp_space(keyframes.1 - keyframes.0)?;
 
-            println!("Cutting video between keyframes (transcoding is required)");
+            let temp_file = self.new_temp_file("temp");
 
-            // cut the bigger part of the video to temp file
-            segment_aligned(&self.path.to_string_lossy(), &temp_file, keyframes, self.dry_run)?;
+            eprintln!("Cutting video between keyframes (transcoding is required)");
 
-            println!("Cutting the resulting video to exact size");
+            // make sure the temp file is deleted later, even if cutting fails
+            let _x = self.temp_path(&temp_file);
 
-            // make sure the temp file is deleted later
-            let _x = util::TempFile(&temp_file);
+            // cut the bigger part of the video to temp file
+            self.segment_aligned(&self.path.to_string_lossy(), &temp_file, keyframes)?;
+
+            eprintln!("Cutting the resulting video to exact size");
 
             // offset is difference between keyframe and actual wanted region
             let offset: u64 = region.0.saturating_sub(keyframes.0);
@@ -52,58 +109,373 @@ impl VideoFile {
             // actually requested length of video (cutting off extra from keyframe)
             let length: u64 = region.1 - region.0;
 
+            // audio packets do not have to line up with the cut either, audio set by the preset
+            // is encoded anyway
+            let audio_file = util::unique_temp_path(&self.tmp_dir(), "audio.mka");
+
+            let audio = match self.preset.as_ref().and_then(|x| x.audio_args()) {
+                Some(args) => AudioCut::Encode(args),
+                None => self.audio_cut(region, &audio_file).inspect_err(|_| {
+                    let _ = std::fs::remove_file(&audio_file);
+                })?,
+            };
+
+            // only spliced audio is written to the temp file
+            let _y = matches!(audio, AudioCut::Spliced { .. }).then(|| self.temp_path(&audio_file));
+
+            // tags come from this file, not from the temp file
+            let metadata = self.metadata_args(region.0)?;
+
+            // so is rotation and HDR side data, the temp file is only stream copied though
+            let props = self.get_video_props().map_err(|x| self.report.fail(x))?;
+
+            let span = (offset, offset + length);
+
             // cut and transcode the actual video
-            segment_not_aligned(
-                &temp_file,
-                &dest,
-                (offset, offset + length),
-                self.dry_run
-            )
+            if let AudioCut::Spliced { lead } = audio {
+                // audio is copied with the video and replaced afterwards
+                let video_file = util::unique_temp_path(&self.tmp_dir(), &Path::new(dest).file_name().unwrap_or_default().to_string_lossy());
+                let _z = self.temp_path(&video_file);
+
+                self.segment_not_aligned(&temp_file, &video_file, span, None, metadata, &props)?;
+                self.replace_audio(&video_file, &audio_file, lead, dest, length)?;
+            } else {
+                let audio_args = match audio {
+                    AudioCut::Encode(args) => Some(args),
+                    _ => None,
+                };
+
+                self.segment_not_aligned(&temp_file, dest, span, audio_args, metadata, &props)?;
+            }
+            self.report_cut(region, region, length);
+
+            Ok(region)
         }
     }
-}
 
-/// Extract segment that is aligned on keyframes
-fn segment_aligned(source: &str, dest: &str, span: (u64, u64), dry_run: bool) -> Result<(), u8> {
-    let mut cmd = Command::new("ffmpeg");
-    cmd.args([
-        "-i", source,
-        "-vcodec", "copy",
-    ]);
-    cmd.args(COMMON_FFMPEG_ARGS);
-
-    // simple copy on keyframes
-    cmd.args([
-        "-ss".into(), format!("{}us", span.0),
-        "-to".into(), format!("{}us", span.1),
-    ]);
-    cmd.arg(dest);
+    /// Remove a segment from the video and join the rest into dest
+    pub fn remove_segment(&self, region: Span, force_align_keyframes: bool, dest: &str) -> crate::ExitResult {
+        let duration = self.get_duration_or_print()?;
+
+        // the parts before and after the removed region, empty parts are skipped
+        let parts: Vec<Span> = [(0, region.0), (region.1, duration)].into_iter()
+            .filter(|(start, end)| start < end)
+            .collect();
+
+        if parts.is_empty() {
+            self.report.error("Removing the whole video would leave nothing behind");
+            return Err(1);
+        }
+
+        // parts aligned to keyframes may be longer than requested
+        let mut written: Vec<Span> = vec![];
+
+        self.write_output(dest, |dest| {
+            if let [part] = parts.as_slice() {
+                written.push(self.cut_segment(*part, force_align_keyframes, dest)?);
+                return Ok(());
+            }
+
+            self.check_temp_space(parts.iter().map(|(start, end)| end - start).sum())?;
+
+            let temp_files: Vec<String> = (0..parts.len())
+                .map(|i| self.new_temp_file(&format!("temp{}", i)))
+                .collect();
+
+            // make sure the temp files are deleted later
+            let _x: Vec<_> = temp_files.iter().map(|x| self.temp_path(x)).collect();
+
+            for (part, temp_file) in parts.iter().zip(&temp_files) {
+                written.push(self.cut_segment(*part, force_align_keyframes, temp_file)?);
+            }
+
+            eprintln!("Joining the parts together");
+
+            // parts are cut from this file so tags and streams come from it as well
+            self.concat_to(&temp_files, dest)
+        })?;
+
+        self.verify_output(dest, || Some(written.iter().map(|x| self.span_length(*x)).sum()), 0)
+    }
+
+    /// Split video at each of the split points, output file names are generated with `dest_for`
+    /// from index and span of the part
+    pub fn split(&self, split_points: &[u64], force_align_keyframes: bool, dest_for: impl Fn(usize, Span) -> Result<String, u8>) -> crate::ExitResult {
+        let duration = self.get_duration_or_print()?;
+
+        let mut points: Vec<u64> = vec![0];
+        for point in split_points {
+            let point = if force_align_keyframes {
+                // every part has to start on a keyframe
+                let keyframe = self.snap_split_point(*point).map_err(|x| self.report.fail(x))?;
+
+                if keyframe != *point {
+                    eprintln!("Split point {} moved to keyframe at {}", secs(*point as i64), secs(keyframe as i64));
+                }
+
+                if point.abs_diff(keyframe) > self.warn_extra {
+                    self.report.warning(format!(
+                        "split point {} moved by {} to a keyframe, more than {}",
+                        secs(*point as i64), secs(keyframe as i64 - *point as i64), secs(self.warn_extra as i64),
+                    ));
+                }
+
+                keyframe
+            } else {
+                *point
+            };
+
+            // ignore points outside the video or duplicates after snapping
+            if point > *points.last().unwrap() && point < duration {
+                points.push(point);
+            }
+        }
+        points.push(duration);
+
+        for (i, part) in points.windows(2).enumerate() {
+            let span = (part[0], part[1]);
+            self.extract_segment(span, force_align_keyframes, &dest_for(i, span)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Split points for splitting the whole video at each interval
+    pub fn interval_split_points(&self, interval: u64) -> Result<Vec<u64>, u8> {
+        if interval == 0 {
+            self.report.error("Split interval cannot be zero");
+            return Err(1);
+        }
 
-    if dry_run {
-        cmd.print_escaped_cmd()
-    } else {
-        cmd.status()
-            .expect("Error executing ffmpeg")
-            .to_exitcode()
+        let duration = self.get_duration_or_print()?;
+
+        Ok((1..).map(|x| x * interval).take_while(|x| *x < duration).collect())
+    }
+
+    fn get_duration_or_print(&self) -> Result<u64, u8> {
+        self.get_duration()
+            .map_err(|x| self.report.fail(x))
+    }
+
+    /// Extract segment that is aligned on keyframes
+    fn segment_aligned(&self, source: &str, dest: &str, span: Span) -> crate::ExitResult {
+        let mut cmd = self.ffmpeg_cmd();
+
+        // seeking on the input jumps straight to the keyframe instead of reading everything
+        // before it
+        cmd.args(seek_args(span));
+        cmd.args(["-i", source]);
+        cmd.args([
+            "-vcodec", "copy",
+            "-acodec", "copy",
+        ]);
+        cmd.args(self.timestamp_args(dest, self.keep_timestamps));
+        cmd.args(self.metadata_args(span.0)?);
+        cmd.args(self.remux_args_for(source, dest, true)?);
+        cmd.arg(dest);
+
+        self.run_ffmpeg(&cmd, dest, span.1.saturating_sub(span.0))
+    }
+
+    /// Extract segment that is not aligned at keyframes (transcoding is required), audio is
+    /// copied unless `audio_args` are given, `metadata` are arguments from `metadata_args`
+    fn segment_not_aligned(&self, source: &str, dest: &str, span: Span, audio_args: Option<Vec<String>>, metadata: Vec<String>, props: &VideoProps) -> crate::ExitResult {
+        // input seeking is frame accurate when decoding, frames before the start are decoded
+        // but dropped
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.args(seek_args(span));
+        cmd.args(props.input_args());
+        cmd.args(["-i", source]);
+
+        if let Some(preset) = &self.preset {
+            cmd.args(preset.video_args());
+
+            if props.is_hdr() && preset.pix_fmt.as_deref().is_some_and(|x| !x.contains("10") && !x.contains("12")) {
+                self.report.warning(format!(
+                    "preset {:?} encodes HDR video with 8-bit pixel format {}, re-encoded parts will not match",
+                    preset.name, preset.pix_fmt.as_deref().unwrap_or_default(),
+                ));
+            }
+        }
+
+        // colors and HDR side data of the source keep the re-encoded part matching the source
+        let (color_args, warning) = props.encoder_args(self.preset.as_ref().map(|x| x.encoder.as_str()));
+        if let Some(warning) = warning {
+            self.report.warning(warning);
+        }
+        cmd.args(color_args);
+
+        // do not re-encode audio unless needed
+        cmd.args(audio_args.unwrap_or_else(|| vec!["-acodec".into(), "copy".into()]));
+        cmd.args(self.timestamp_args(dest, self.keep_timestamps));
+        cmd.args(metadata);
+        cmd.args(self.remux_args_for(source, dest, false)?);
+        cmd.arg(dest);
+
+        let length = span.1.saturating_sub(span.0);
+        self.run_ffmpeg(&cmd, dest, length)?;
+
+        // only cuts count as re-encoded, previews and such are encoded anyway
+        if !self.dry_run {
+            self.report.encoded(length);
+        }
+
+        Ok(())
     }
 }
 
-/// Extract segment that is not aligned at keyframes (transcoding is required)
-fn segment_not_aligned(source: &str, dest: &str, span: (u64, u64), dry_run: bool) -> Result<(), u8> {
-    let mut cmd = Command::new("ffmpeg");
-    cmd.args(["-i", source]);
-    cmd.args(COMMON_FFMPEG_ARGS);
-    cmd.args([
-        "-ss".into(), format!("{}us", span.0),
-        "-to".into(), format!("{}us", span.1),
-    ]);
-    cmd.arg(dest);
-
-    if dry_run {
-        cmd.print_escaped_cmd()
-    } else {
-        cmd.status()
-            .expect("Error executing ffmpeg")
-            .to_exitcode()
+#[cfg(test)]
+mod tests {
+    use std::{path::{Path, PathBuf}, process::Command};
+
+    use super::*;
+    use crate::{plan::Step, progress::ProgressMode};
+
+    #[test]
+    fn test_seek_args() {
+        assert_eq!(seek_args((2_000_000, 3_500_000)), ["-ss", "2000000us", "-t", "1500000us"]);
+    }
+
+    /// 10s clip at 25fps with keyframe every 2s, b-frames and an AAC audio track, in its own
+    /// directory
+    fn test_clip(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rcut-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let clip = dir.join("clip.mkv");
+        let status = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-nostdin", "-y"])
+            .args(["-f", "lavfi", "-i", "testsrc=duration=10:size=320x240:rate=25"])
+            .args(["-f", "lavfi", "-i", "sine=frequency=440:duration=10"])
+            .args(["-c:v", "mpeg4", "-g", "50", "-bf", "2", "-q:v", "5"])
+            .args(["-c:a", "aac", "-metadata:s:a:0", "language=eng"])
+            .arg(&clip)
+            .status()
+            .expect("Error executing ffmpeg");
+        assert!(status.success());
+
+        clip
+    }
+
+    fn count_frames(path: &Path) -> u64 {
+        let output = Command::new("ffprobe")
+            .args(["-v", "error", "-count_frames", "-select_streams", "v:0"])
+            .args(["-show_entries", "stream=nb_read_frames", "-of", "csv=p=0"])
+            .arg(path)
+            .output()
+            .expect("Error executing ffprobe");
+
+        String::from_utf8_lossy(&output.stdout).trim().parse().unwrap()
+    }
+
+    /// Decode the first audio stream into mono samples, only span of it if given
+    fn decode_audio(path: &Path, span: Option<Span>) -> Vec<f32> {
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-loglevel", "error", "-nostdin"]);
+        if let Some(span) = span {
+            cmd.args(seek_args(span));
+        }
+        cmd.arg("-i").arg(path);
+        cmd.args(["-map", "0:a:0", "-ac", "1", "-f", "f32le", "-"]);
+
+        let output = cmd.output().expect("Error executing ffmpeg");
+        assert!(output.status.success());
+
+        output.stdout.chunks_exact(4).map(|x| f32::from_le_bytes(x.try_into().unwrap())).collect()
+    }
+
+    fn clip_file(clip: &Path) -> VideoFile {
+        VideoFile {
+            path: clip.to_path_buf(),
+            progress: ProgressMode::None,
+            tmp_dir: clip.parent().map(Path::to_path_buf),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg, run with --ignored"]
+    fn test_cut_at_keyframes_is_frame_accurate() {
+        let clip = test_clip("aligned");
+        let dest = clip.with_file_name("aligned.mkv");
+
+        let vfile = clip_file(&clip);
+        vfile.extract_segment((2_000_000, 6_000_000), true, &dest.to_string_lossy()).unwrap();
+
+        assert_eq!(count_frames(&dest), 100);
+        assert_eq!(vfile.with_path(&dest).get_start_time(), Ok(0));
+
+        let report = vfile.with_path(&dest).get_sync_report().unwrap();
+        assert_eq!(report.problems(Some(4_000_000), 0), Vec::<String>::new());
+
+        let _ = std::fs::remove_dir_all(clip.parent().unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg, run with --ignored"]
+    fn test_cut_between_keyframes_is_frame_accurate() {
+        let clip = test_clip("exact");
+        let dest = clip.with_file_name("exact.mkv");
+
+        let vfile = clip_file(&clip);
+        vfile.extract_segment((2_520_000, 5_000_000), false, &dest.to_string_lossy()).unwrap();
+
+        // 2.52s to 5s is frames 63 to 124
+        assert_eq!(count_frames(&dest), 62);
+        assert_eq!(vfile.with_path(&dest).get_start_time(), Ok(0));
+
+        // audio around the cut is spliced, it has to stay in sync with the re-encoded video
+        let report = vfile.with_path(&dest).get_sync_report().unwrap();
+        assert_eq!(report.problems(Some(2_480_000), 0), Vec::<String>::new());
+
+        let _ = std::fs::remove_dir_all(clip.parent().unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg, run with --ignored"]
+    fn test_cut_between_keyframes_splices_audio() {
+        let clip = test_clip("splice");
+        let dest = clip.with_file_name("splice.mp4");
+        let region = (2_520_000, 5_000_000);
+
+        let vfile = clip_file(&clip);
+        vfile.extract_segment(region, false, &dest.to_string_lossy()).unwrap();
+
+        // a gap or overlap at either join would move the sine after it out of phase
+        let expected = decode_audio(&clip, Some(region));
+        let actual = decode_audio(&dest, None);
+        assert!(actual.len().abs_diff(expected.len()) <= 1, "{} samples, expected {}", actual.len(), expected.len());
+
+        let error = expected.iter().zip(&actual).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max);
+        // sine is 0.125 loud, being out of phase differs by far more than the encoding does
+        assert!(error < 0.05, "audio differs by up to {}", error);
+
+        // spliced audio keeps its place and tags
+        let output = vfile.with_path(&dest);
+        let streams = output.get_streams().unwrap();
+        assert_eq!(streams.iter().map(|x| x.codec_type.as_str()).collect::<Vec<_>>(), ["video", "audio"]);
+
+        let data = output.probe_json(&["-select_streams", "a:0", "-show_entries", "stream_tags=language"]).unwrap();
+        assert_eq!(data["streams"][0]["tags"]["language"], "eng");
+
+        let _ = std::fs::remove_dir_all(clip.parent().unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg, run with --ignored"]
+    fn test_remove_middle_dry_run() {
+        let clip = test_clip("remove");
+        let dest = clip.with_file_name("removed.mkv");
+
+        let vfile = VideoFile { dry_run: true, ..clip_file(&clip) };
+        vfile.remove_segment((2_000_000, 6_000_000), true, &dest.to_string_lossy()).unwrap();
+
+        // both parts are joined and then removed along with the list, nothing is written
+        let steps = vfile.plan.steps();
+        assert!(steps.iter().any(|x| matches!(x, Step::WriteFile { content, .. } if content.matches("file '").count() == 2)));
+        assert_eq!(steps.iter().filter(|x| matches!(x, Step::RemoveFile { .. })).count(), 3);
+        assert!(!dest.exists());
+
+        let _ = std::fs::remove_dir_all(clip.parent().unwrap());
     }
 }
@@ -0,0 +1,152 @@
+use super::{Span, VideoFile};
+use crate::util;
+
+/// Time of the frame that is on screen at time, that is the last frame starting at or before it
+pub fn frame_shown_at(frames: &[u64], time: u64) -> Option<u64> {
+    frames.iter().copied().filter(|x| *x <= time).max()
+}
+
+/// Keyframe where decoding has to start to get the frame at time, keyframes after it do not
+/// matter so this works in the last GOP of the file as well
+pub fn decode_start(keyframes: &[u64], time: u64) -> Option<u64> {
+    VideoFile::snap_point(keyframes, time, true, false)
+}
+
+/// Group outputs by the keyframe decoding starts at, in order of first appearance, so frames from
+/// the same GOP are decoded only once
+pub fn group_by_keyframe(keyframes: &[u64]) -> Vec<(u64, Vec<usize>)> {
+    let mut groups: Vec<(u64, Vec<usize>)> = vec![];
+
+    for (i, keyframe) in keyframes.iter().enumerate() {
+        match groups.iter_mut().find(|(x, _)| x == keyframe) {
+            Some((_, outputs)) => outputs.push(i),
+            None => groups.push((*keyframe, vec![i])),
+        }
+    }
+
+    groups
+}
+
+impl VideoFile {
+    /// Get presentation times of all video frames in the region, relative to the start of the file
+    pub fn get_frame_times(&self, region: Span) -> Result<Vec<u64>, (String, u8)> {
+        self.get_packet_times("v:0", region)
+    }
+
+    /// Save the frames shown at each of the times as images, all of them are written by a single
+    /// ffmpeg
+    ///
+    /// Each frame is decoded starting from the keyframe before it, so only a small part of the
+    /// file is read for each one, frames in the same GOP share one input
+    pub fn grab_frames(&self, times: &[u64], dests: &[String]) -> crate::ExitResult {
+        assert_eq!(times.len(), dests.len(), "Each time needs its own output");
+
+        for dest in dests {
+            self.check_output(dest, &[&self.path])?;
+        }
+
+        // keyframe to decode from and the frame on screen for each time
+        let mut starts: Vec<u64> = vec![];
+        let mut frames: Vec<u64> = vec![];
+
+        for time in times {
+            let keyframes = self.get_keyframes_around((*time, *time))
+                .map_err(|x| self.report.fail(x))?;
+
+            let Some(keyframe) = decode_start(&keyframes, *time) else {
+                self.report.error(format!("Could not find keyframe before {}us", time));
+                return Err(1);
+            };
+
+            let frame = self.get_frame_times((keyframe, *time + 1))
+                .map(|x| frame_shown_at(&x, *time).unwrap_or(*time))
+                .map_err(|x| self.report.fail(x))?;
+
+            starts.push(keyframe);
+            frames.push(frame);
+        }
+
+        // outputs are moved into place only if all of them succeed
+        let outputs: Vec<util::AtomicOutput> = dests.iter()
+            .filter(|_| !self.dry_run)
+            .map(|x| util::AtomicOutput::new(x))
+            .collect();
+
+        let paths: Vec<&str> = match self.dry_run {
+            true => dests.iter().map(|x| x.as_str()).collect(),
+            false => outputs.iter().map(|x| x.path()).collect(),
+        };
+
+        let mut cmd = self.ffmpeg_cmd();
+        let mut output_args: Vec<String> = vec![];
+
+        for (input, (keyframe, indexes)) in group_by_keyframe(&starts).into_iter().enumerate() {
+            // the input starts at the keyframe, each output skips the decoded frames before the
+            // wanted one
+            cmd.args(["-ss".into(), format!("{}us", keyframe)]);
+            cmd.arg("-i").arg(&self.path);
+
+            for i in indexes {
+                output_args.extend([
+                    "-map".into(), format!("{}:v:0", input),
+                    "-ss".into(), format!("{}us", frames[i].saturating_sub(keyframe)),
+                    "-frames:v".into(), "1".into(),
+                    // keep the format, temporary output would be treated as image sequence
+                    "-update".into(), "1".into(),
+                    paths[i].into(),
+                ]);
+            }
+        }
+
+        cmd.args(output_args);
+
+        // partial outputs are removed when dropped
+        self.run_ffmpeg_to(&cmd, &paths, 0)?;
+
+        for (output, dest) in outputs.into_iter().zip(dests) {
+            self.commit_output(output)?;
+            self.report.output(dest);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_shown_at() {
+        let frames = vec![1_000_000, 1_040_000, 1_080_000, 1_120_000];
+
+        assert_eq!(frame_shown_at(&frames, 1_050_000), Some(1_040_000));
+        assert_eq!(frame_shown_at(&frames, 1_080_000), Some(1_080_000));
+        assert_eq!(frame_shown_at(&frames, 5_000_000), Some(1_120_000));
+        assert_eq!(frame_shown_at(&frames, 500_000), None);
+    }
+
+    #[test]
+    fn test_group_by_keyframe() {
+        let starts = vec![2_000_000, 0, 2_000_000, 4_000_000, 0];
+
+        assert_eq!(group_by_keyframe(&starts), vec![
+            (2_000_000, vec![0, 2]),
+            (0, vec![1, 4]),
+            (4_000_000, vec![3]),
+        ]);
+        assert_eq!(group_by_keyframe(&[]), vec![]);
+    }
+
+    #[test]
+    fn test_decode_start() {
+        let keyframes = vec![0, 2_000_000, 4_000_000];
+
+        assert_eq!(decode_start(&keyframes, 3_000_000), Some(2_000_000));
+        assert_eq!(decode_start(&keyframes, 2_000_000), Some(2_000_000));
+
+        // last GOP, there is no keyframe after it
+        assert_eq!(decode_start(&keyframes, 5_900_000), Some(4_000_000));
+        assert_eq!(decode_start(&[1_000_000], 500_000), None);
+    }
+}
@@ -0,0 +1,90 @@
+use super::{Span, VideoFile};
+
+/// Keyframes that start an open GOP, packets are (pts, is keyframe) in decoding order
+///
+/// Frames that follow a keyframe in decoding order but are shown before it (leading pictures)
+/// reference the previous GOP, so they cannot be decoded when the stream is cut at the keyframe.
+/// This catches HEVC CRA frames and H.264 I-frames that are not IDR (recovery points)
+pub fn open_gop_keyframes(packets: &[(u64, bool)]) -> Vec<u64> {
+    let mut open: Vec<u64> = vec![];
+
+    for (i, (pts, key)) in packets.iter().enumerate() {
+        if !key {
+            continue;
+        }
+
+        let has_leading = packets[i + 1..].iter()
+            .take_while(|(_, key)| !key)
+            .any(|(x, _)| x < pts);
+
+        if has_leading {
+            open.push(*pts);
+        }
+    }
+
+    open.sort();
+    open
+}
+
+/// Keyframes without the open GOP ones, times within a millisecond are the same frame as
+/// rounding differs between frames and packets
+pub fn without_open_gops(keyframes: &[u64], open: &[u64]) -> Vec<u64> {
+    keyframes.iter()
+        .filter(|x| !open.iter().any(|y| y.abs_diff(**x) <= 1_000))
+        .cloned()
+        .collect()
+}
+
+impl VideoFile {
+    /// Get pts and key flag of video packets in the region in decoding order, relative to the
+    /// start of the file
+    fn get_video_packets(&self, region: Span) -> Result<Vec<(u64, bool)>, (String, u8)> {
+        let start_time = self.get_start_time()?;
+
+        let data = self.probe_json(&[
+            "-select_streams", "v:0",
+            "-read_intervals", &format!("{}us%{}us", region.0 as i64 + start_time, region.1 as i64 + start_time),
+            "-show_entries", "packet=pts_time,flags",
+        ])?;
+
+        Ok(data["packets"].as_array().cloned().unwrap_or_default().iter()
+            .filter_map(|x| {
+                let pts = x["pts_time"].as_str()?.parse::<f64>().ok()?;
+                let key = x["flags"].as_str().is_some_and(|x| x.contains('K'));
+
+                Some((((pts * 1_000_000.0).round() as i64 - start_time).max(0) as u64, key))
+            })
+            .collect())
+    }
+
+    /// Get keyframes in the region that start an open GOP
+    pub fn get_open_gop_keyframes(&self, region: Span) -> Result<Vec<u64>, (String, u8)> {
+        Ok(open_gop_keyframes(&self.get_video_packets(region)?))
+    }
+
+    /// Check if stream copy can start at time without undecodable leading frames
+    pub(super) fn is_random_access_point(&self, time: u64) -> Result<bool, (String, u8)> {
+        // leading pictures come right after the keyframe, one second is plenty
+        let open = self.get_open_gop_keyframes((time, time + 1_000_000))?;
+
+        Ok(without_open_gops(&[time], &open).len() == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_gop_keyframes() {
+        // closed GOP at 0, open GOP at 400 with two leading b-frames, closed GOP at 800
+        let packets = [
+            (0, true), (120, false), (40, false), (80, false), (240, false), (160, false), (200, false),
+            (400, true), (320, false), (360, false), (520, false), (440, false), (480, false),
+            (800, true), (920, false), (840, false),
+        ];
+
+        assert_eq!(open_gop_keyframes(&packets), vec![400]);
+        assert_eq!(without_open_gops(&[0, 400_500, 800], &[400_000]), vec![0, 800]);
+    }
+}
@@ -0,0 +1,359 @@
+use std::path::Path;
+
+use super::{manifest::{resolve, Manifest, Segment}, VideoFile};
+use crate::{plan::Step, util};
+
+/// Type of segments written when splitting for HLS
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HlsSegmentType {
+    /// MPEG-TS segments
+    #[default]
+    Ts,
+
+    /// Fragmented MP4 segments with separate init segment
+    Fmp4,
+}
+
+/// Check if the path looks like HLS playlist
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Choose segment boundaries from keyframes so that every segment is at least target long
+///
+/// Returned times are the starts of each segment except the first one, which always starts at zero
+pub fn hls_boundaries(keyframes: &[u64], target: u64) -> Vec<u64> {
+    let mut boundaries: Vec<u64> = vec![];
+    let mut last = 0;
+
+    for keyframe in keyframes {
+        if *keyframe >= last + target {
+            boundaries.push(*keyframe);
+            last = *keyframe;
+        }
+    }
+
+    boundaries
+}
+
+impl Manifest {
+    /// Parse HLS media playlist, relative URIs are resolved from base
+    pub fn parse_hls(content: &str, base: &Path) -> Result<Self, String> {
+        let mut manifest = Self::default();
+        let mut duration: Option<u64> = None;
+
+        for line in content.lines().map(str::trim).filter(|x| !x.is_empty()) {
+            if let Some(value) = line.strip_prefix("#EXTINF:") {
+                let value = value.split(',').next().unwrap_or_default();
+                let secs = value.parse::<f64>().map_err(|_| format!("Invalid segment duration {:?}", value))?;
+                duration = Some((secs * 1_000_000.0).round() as u64);
+            } else if let Some(value) = line.strip_prefix("#EXT-X-MAP:") {
+                let uri = value.split(',')
+                    .find_map(|x| x.strip_prefix("URI="))
+                    .ok_or_else(|| "EXT-X-MAP without URI".to_string())?;
+                manifest.init = Some(resolve(base, uri.trim_matches('"'))?);
+            } else if line.starts_with("#EXT-X-STREAM-INF") {
+                return Err("Master playlists are not supported, use one of the media playlists".into());
+            } else if line.starts_with("#EXT-X-BYTERANGE") {
+                return Err("Playlists with byte ranges are not supported".into());
+            } else if !line.starts_with('#') {
+                let duration = duration.take().ok_or_else(|| format!("Segment {:?} has no duration", line))?;
+                manifest.segments.push(Segment {
+                    path: resolve(base, line)?,
+                    duration,
+                });
+            }
+        }
+
+        if manifest.segments.is_empty() {
+            return Err("Playlist has no segments".into());
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl VideoFile {
+    /// Split the video into segments at keyframes at least target long and write HLS playlist
+    /// pointing to them, segments are named after the playlist
+    pub fn split_hls(&self, target: u64, segment_type: HlsSegmentType, playlist: &str) -> crate::ExitResult {
+        if target == 0 {
+            self.report.error("Segment duration cannot be zero");
+            return Err(1);
+        }
+
+        let playlist_path = Path::new(playlist);
+        let dir = playlist_path.parent().unwrap_or(Path::new(""));
+        let stem = playlist_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+        let keyframes = self.get_keyframes(None).map_err(|x| self.report.fail(x))?;
+        let boundaries = hls_boundaries(&keyframes, target);
+
+        let (extension, init) = match segment_type {
+            HlsSegmentType::Ts => ("ts", None),
+            HlsSegmentType::Fmp4 => ("m4s", Some(dir.join(format!("{}_init.mp4", stem)).to_string_lossy().to_string())),
+        };
+
+        let segments: Vec<String> = (0..=boundaries.len())
+            .map(|i| dir.join(format!("{}{:05}.{}", stem, i, extension)).to_string_lossy().to_string())
+            .collect();
+
+        // ffmpeg overwrites without asking so check everything before writing anything
+        let outputs: Vec<&String> = init.iter().chain(&segments).collect();
+        self.check_output(playlist, &[&self.path])?;
+        for output in &outputs {
+            self.check_output(output, &[&self.path])?;
+        }
+
+        let result = match &init {
+            None => self.split_hls_ts(&boundaries, &dir.join(format!("{}%05d.ts", stem)), playlist),
+            Some(init) => self.split_hls_fmp4(&keyframes, &boundaries, &segments, init, playlist),
+        };
+
+        if self.dry_run {
+            return result;
+        }
+
+        // segments without the playlist are useless
+        if result.is_err() {
+            for output in &outputs {
+                let _ = std::fs::remove_file(output);
+            }
+
+            return result;
+        }
+
+        for output in outputs {
+            self.report.output(output);
+        }
+        self.report.output(playlist);
+
+        Ok(())
+    }
+
+    /// Write mpegts segments and the playlist with the segment muxer
+    fn split_hls_ts(&self, boundaries: &[u64], pattern: &Path, playlist: &str) -> crate::ExitResult {
+        let output = util::AtomicOutput::new(playlist);
+        let list = if self.dry_run { playlist } else { output.path() };
+
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.arg("-i").arg(&self.path);
+        cmd.args(["-map", "0:v:0", "-map", "0:a?", "-c", "copy"]);
+        cmd.args(["-f", "segment", "-segment_format", "mpegts"]);
+
+        // without any boundaries the whole video is one segment
+        if !boundaries.is_empty() {
+            let times: Vec<String> = boundaries.iter()
+                .map(|x| format!("{:.6}", *x as f64 / 1_000_000.0))
+                .collect();
+            cmd.args(["-segment_times", &times.join(",")]);
+
+            // the times are exactly on keyframes, make sure rounding does not skip them
+            cmd.args(["-segment_time_delta", "0.001"]);
+        }
+
+        cmd.args(["-segment_list", list, "-segment_list_type", "m3u8"]);
+        cmd.arg(pattern);
+
+        let length = self.get_duration().unwrap_or(0);
+        self.run_ffmpeg(&cmd, list, length)?;
+
+        if self.dry_run {
+            return Ok(());
+        }
+
+        self.commit_output(output)
+    }
+
+    /// Write fragmented MP4 segments, init segment and the playlist
+    ///
+    /// The hls muxer has no way to split at given times, so it splits at every keyframe into temp
+    /// directory and the pieces are joined at the boundaries, fragments can follow each other in
+    /// one segment
+    fn split_hls_fmp4(&self, keyframes: &[u64], boundaries: &[u64], segments: &[String], init: &str, playlist: &str) -> crate::ExitResult {
+        let duration = self.get_duration().map_err(|x| self.report.fail(x))?;
+        self.check_temp_space(duration)?;
+
+        let temp_dir = util::unique_temp_path(&self.tmp_dir(), "hls");
+        let _x = self.temp_path(&temp_dir);
+        let temp = Path::new(&temp_dir);
+        let pieces: Vec<String> = (0..keyframes.len())
+            .map(|i| temp.join(format!("{:06}.m4s", i)).to_string_lossy().to_string())
+            .collect();
+
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.arg("-i").arg(&self.path);
+        cmd.args(["-map", "0:v:0", "-map", "0:a?", "-c", "copy"]);
+        cmd.args([
+            "-f".into(), "hls".into(),
+            // shorter than any GOP so every keyframe starts a new piece
+            "-hls_time".into(), "0.001".into(),
+            "-hls_playlist_type".into(), "vod".into(),
+            "-hls_segment_type".into(), "fmp4".into(),
+            "-hls_fmp4_init_filename".into(), "init.mp4".into(),
+            "-hls_segment_filename".into(), temp.join("%06d.m4s").to_string_lossy().to_string(),
+        ]);
+
+        let temp_playlist = temp.join("pieces.m3u8").to_string_lossy().to_string();
+        cmd.arg(&temp_playlist);
+
+        // pieces that go into each segment
+        let mut groups: Vec<Vec<String>> = vec![vec![]; segments.len()];
+        for (keyframe, piece) in keyframes.iter().zip(&pieces) {
+            groups[boundaries.iter().filter(|x| *x <= keyframe).count()].push(piece.clone());
+        }
+
+        let content = hls_playlist(boundaries, duration, segments, init);
+        let temp_init = temp.join("init.mp4").to_string_lossy().to_string();
+
+        if self.dry_run {
+            self.run_ffmpeg(&cmd, &temp_playlist, duration)?;
+            self.plan.push(Step::CopyFile { from: temp_init, to: init.into() });
+            for (group, segment) in groups.into_iter().zip(segments) {
+                self.plan.push(Step::JoinFiles { from: group, to: segment.clone() });
+            }
+            self.plan.push(Step::WriteFile { path: playlist.into(), content });
+
+            return Ok(());
+        }
+
+        if let Err(err) = std::fs::create_dir_all(temp) {
+            self.report.error(format!("Could not create temp directory {:?}: {}", temp, err));
+            return Err(1);
+        }
+
+        self.run_ffmpeg(&cmd, &temp_playlist, duration)?;
+
+        // keyframes that were not split at would put the pieces at wrong times
+        let count = std::fs::read_dir(temp).map_or(0, |x| {
+            x.flatten().filter(|x| x.path().extension().is_some_and(|x| x == "m4s")).count()
+        });
+
+        if count != keyframes.len() {
+            self.report.error(format!("Expected {} pieces from ffmpeg, one for each keyframe, but got {}", keyframes.len(), count));
+            return Err(1);
+        }
+
+        self.join_files(&[temp_init], init)?;
+        for (group, segment) in groups.iter().zip(segments) {
+            self.cancel.check()?;
+            self.join_files(group, segment)?;
+        }
+
+        let output = util::AtomicOutput::new(playlist);
+        if let Err(err) = std::fs::write(output.path(), content) {
+            self.report.error(format!("Could not write playlist {:?}: {}", playlist, err));
+            return Err(1);
+        }
+
+        self.commit_output(output)
+    }
+
+    /// Join files byte by byte into dest, dest is only written if all of them are read
+    fn join_files(&self, files: &[String], dest: &str) -> crate::ExitResult {
+        let output = util::AtomicOutput::new(dest);
+
+        let result = std::fs::File::create(output.path()).and_then(|mut writer| {
+            for file in files {
+                std::io::copy(&mut std::fs::File::open(file)?, &mut writer)?;
+            }
+
+            Ok(())
+        });
+
+        if let Err(err) = result {
+            self.report.error(format!("Could not join segments into {:?}: {}", dest, err));
+            return Err(1);
+        }
+
+        self.commit_output(output)
+    }
+}
+
+/// HLS playlist for fragmented MP4 segments that start at the boundaries, paths in it are
+/// relative to the playlist
+fn hls_playlist(boundaries: &[u64], duration: u64, segments: &[String], init: &str) -> String {
+    let name = |path: &str| Path::new(path).file_name().unwrap_or_default().to_string_lossy().to_string();
+
+    let starts = std::iter::once(0).chain(boundaries.iter().cloned());
+    let ends = boundaries.iter().cloned().chain(std::iter::once(duration));
+    let durations: Vec<u64> = starts.zip(ends).map(|(start, end)| end.saturating_sub(start)).collect();
+
+    let mut content = format!(
+        "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-INDEPENDENT-SEGMENTS\n#EXT-X-MAP:URI=\"{}\"\n",
+        durations.iter().max().unwrap_or(&0).div_ceil(1_000_000),
+        name(init),
+    );
+
+    for (duration, segment) in durations.iter().zip(segments) {
+        content += &format!("#EXTINF:{:.6},\n{}\n", *duration as f64 / 1_000_000.0, name(segment));
+    }
+
+    content + "#EXT-X-ENDLIST\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_hls_boundaries() {
+        let keyframes = vec![0, 2_000_000, 4_000_000, 5_000_000, 9_000_000, 10_000_000, 12_000_000];
+
+        assert_eq!(hls_boundaries(&keyframes, 4_000_000), vec![4_000_000, 9_000_000]);
+        assert_eq!(hls_boundaries(&keyframes, 100_000_000), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_hls_playlist() {
+        let segments = vec!["/out/video00000.m4s".to_string(), "/out/video00001.m4s".to_string()];
+
+        assert_eq!(hls_playlist(&[4_000_000], 6_500_000, &segments, "/out/video_init.mp4"), "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:4
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MAP:URI=\"video_init.mp4\"
+#EXTINF:4.000000,
+video00000.m4s
+#EXTINF:2.500000,
+video00001.m4s
+#EXT-X-ENDLIST
+");
+    }
+
+    #[test]
+    fn test_parse_playlist() {
+        let content = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:6
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:6.000000,
+seg0.m4s
+#EXTINF:4.5,
+seg1.m4s
+#EXTINF:6,
+seg2.m4s
+#EXT-X-ENDLIST
+";
+
+        let manifest = Manifest::parse_hls(content, Path::new("/hls")).unwrap();
+        assert_eq!(manifest.init, Some(PathBuf::from("/hls/init.mp4")));
+        assert_eq!(manifest.segments.len(), 3);
+        assert_eq!(manifest.segments[1], Segment { path: PathBuf::from("/hls/seg1.m4s"), duration: 4_500_000 });
+        assert_eq!(manifest.extension(), "mp4");
+
+        // region inside second segment
+        assert_eq!(manifest.segments_for((7_000_000, 8_000_000)), Some((6_000_000, 1..2)));
+
+        // region spanning all segments
+        assert_eq!(manifest.segments_for((1_000_000, 15_000_000)), Some((0, 0..3)));
+
+        assert_eq!(manifest.segments_for((20_000_000, 21_000_000)), None);
+
+        assert!(Manifest::parse_hls("#EXTM3U\n#EXTINF:1,\nhttp://x/a.ts\n", Path::new("")).is_err());
+        assert!(Manifest::parse_hls("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\na.m3u8\n", Path::new("")).is_err());
+    }
+}
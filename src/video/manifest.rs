@@ -0,0 +1,522 @@
+use std::{ops::Range, path::{Path, PathBuf}};
+
+use super::{Span, VideoFile};
+use crate::{plan::Step, util};
+
+/// Media segment of a manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub path: PathBuf,
+
+    /// Duration of the segment in micros
+    pub duration: u64,
+}
+
+/// Local HLS media playlist or DASH manifest resolved into list of segment files
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Initialization segment (fragmented MP4 / WebM)
+    pub init: Option<PathBuf>,
+
+    pub segments: Vec<Segment>,
+}
+
+/// Check if the path looks like HLS playlist or DASH manifest
+pub fn is_manifest(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x.eq_ignore_ascii_case("m3u8") || x.eq_ignore_ascii_case("mpd"))
+}
+
+/// Resolve URI relative to base, remote URIs are not supported
+pub(super) fn resolve(base: &Path, uri: &str) -> Result<PathBuf, String> {
+    if uri.contains("://") {
+        return Err(format!("Only local manifests are supported, found {:?}", uri));
+    }
+
+    Ok(base.join(uri))
+}
+
+/// First child element with the name, namespaces are ignored
+fn child<'a, 'input>(node: roxmltree::Node<'a, 'input>, name: &str) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|x| x.is_element() && x.tag_name().name() == name)
+}
+
+/// Parse ISO 8601 duration as used in DASH (`PT1H2M3.5S`) into micros
+fn parse_iso_duration(input: &str) -> Option<u64> {
+    let re = regex::Regex::new(r"^P(?:([0-9]+)D)?(?:T(?:([0-9]+)H)?(?:([0-9]+)M)?(?:([0-9.]+)S)?)?$")
+        .expect("Error building duration regex");
+
+    let captures = re.captures(input)?;
+    let number = |i: usize| captures.get(i).map_or(Some(0.0), |x| x.as_str().parse::<f64>().ok());
+
+    let secs = number(1)? * 86_400.0 + number(2)? * 3_600.0 + number(3)? * 60.0 + number(4)?;
+    Some((secs * 1_000_000.0).round() as u64)
+}
+
+/// Fill in DASH segment template identifiers like `$Number%05d$`
+fn fill_template(template: &str, representation: &str, number: u64, time: u64, bandwidth: u64) -> String {
+    let re = regex::Regex::new(r"\$(RepresentationID|Number|Time|Bandwidth)?(?:%0([0-9]+)d)?\$")
+        .expect("Error building template regex");
+
+    re.replace_all(template, |captures: &regex::Captures| {
+        let width = captures.get(2).map_or(0, |x| x.as_str().parse::<usize>().unwrap_or(0));
+        match captures.get(1).map(|x| x.as_str()) {
+            Some("RepresentationID") => representation.to_string(),
+            Some("Number") => format!("{:0width$}", number, width = width),
+            Some("Time") => format!("{:0width$}", time, width = width),
+            Some("Bandwidth") => format!("{:0width$}", bandwidth, width = width),
+            // $$ is escaped dollar sign
+            _ => "$".to_string(),
+        }
+    }).to_string()
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read manifest {:?}: {}", path, err))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+
+        match path.extension().map(|x| x.to_string_lossy().to_lowercase()).as_deref() {
+            Some("mpd") => Self::parse_dash(&content, base),
+            _ => Self::parse_hls(&content, base),
+        }
+    }
+
+    /// Parse DASH manifest using the video representation with highest bandwidth from the first
+    /// period, both `SegmentList` and `SegmentTemplate` (with or without timeline) are supported
+    pub fn parse_dash(content: &str, base: &Path) -> Result<Self, String> {
+        let doc = roxmltree::Document::parse(content)
+            .map_err(|err| format!("Invalid DASH manifest: {}", err))?;
+
+        let mpd = doc.root_element();
+        let total = mpd.attribute("mediaPresentationDuration").and_then(parse_iso_duration);
+        let period = child(mpd, "Period").ok_or("Manifest has no period")?;
+
+        let content_type = |set: &roxmltree::Node| -> String {
+            let mime = set.attribute("mimeType")
+                .or_else(|| child(*set, "Representation").and_then(|x| x.attribute("mimeType")))
+                .unwrap_or_default();
+
+            set.attribute("contentType")
+                .unwrap_or_else(|| mime.split('/').next().unwrap_or_default())
+                .to_string()
+        };
+
+        let sets: Vec<roxmltree::Node> = period.children()
+            .filter(|x| x.is_element() && x.tag_name().name() == "AdaptationSet")
+            .collect();
+
+        let set = sets.iter()
+            .find(|x| content_type(x) == "video")
+            .or(sets.first())
+            .ok_or("Manifest has no adaptation sets")?;
+
+        if sets.iter().any(|x| content_type(x) == "audio") && content_type(set) == "video" {
+            return Err("Manifests with audio in separate adaptation set are not supported".into());
+        }
+
+        let representation = set.children()
+            .filter(|x| x.is_element() && x.tag_name().name() == "Representation")
+            .max_by_key(|x| x.attribute("bandwidth").and_then(|x| x.parse::<u64>().ok()).unwrap_or(0))
+            .ok_or("Adaptation set has no representations")?;
+
+        let representation_id = representation.attribute("id").unwrap_or_default();
+        let bandwidth = representation.attribute("bandwidth").and_then(|x| x.parse::<u64>().ok()).unwrap_or(0);
+
+        // base urls are relative to each other from top to bottom
+        let mut base = base.to_path_buf();
+        for node in [mpd, period, *set, representation] {
+            if let Some(url) = child(node, "BaseURL").and_then(|x| x.text()) {
+                base = resolve(&base, url.trim())?;
+            }
+        }
+
+        // segment information on representation overrides the adaptation set
+        let levels = [representation, *set, period];
+        let find = |name: &str| levels.iter().find_map(|x| child(*x, name));
+        let attribute = |nodes: &[Option<roxmltree::Node>], name: &str| -> Option<String> {
+            nodes.iter().flatten().find_map(|x| x.attribute(name)).map(str::to_string)
+        };
+
+        let mut manifest = Self::default();
+
+        if let Some(list) = find("SegmentList") {
+            let timescale = attribute(&[Some(list)], "timescale").and_then(|x| x.parse::<u64>().ok()).unwrap_or(1);
+            let duration = attribute(&[Some(list)], "duration").and_then(|x| x.parse::<u64>().ok())
+                .ok_or("SegmentList without duration is not supported")?;
+
+            if let Some(init) = child(list, "Initialization").and_then(|x| x.attribute("sourceURL")) {
+                manifest.init = Some(resolve(&base, init)?);
+            }
+
+            for url in list.children().filter(|x| x.is_element() && x.tag_name().name() == "SegmentURL") {
+                let media = url.attribute("media").ok_or("SegmentURL without media")?;
+                manifest.segments.push(Segment {
+                    path: resolve(&base, media)?,
+                    duration: duration * 1_000_000 / timescale,
+                });
+            }
+        } else {
+            // templates can be split between levels so collect them all
+            let templates: Vec<Option<roxmltree::Node>> = levels.iter().map(|x| child(*x, "SegmentTemplate")).collect();
+            if templates.iter().all(Option::is_none) {
+                return Err("Manifest has neither SegmentList nor SegmentTemplate".into());
+            }
+
+            let timescale = attribute(&templates, "timescale").and_then(|x| x.parse::<u64>().ok()).unwrap_or(1);
+            let start_number = attribute(&templates, "startNumber").and_then(|x| x.parse::<u64>().ok()).unwrap_or(1);
+            let media = attribute(&templates, "media").ok_or("SegmentTemplate without media")?;
+
+            if let Some(init) = attribute(&templates, "initialization") {
+                manifest.init = Some(resolve(&base, &fill_template(&init, representation_id, 0, 0, bandwidth))?);
+            }
+
+            // list of (time, duration) in timescale units
+            let mut times: Vec<(u64, u64)> = vec![];
+
+            if let Some(timeline) = templates.iter().flatten().find_map(|x| child(*x, "SegmentTimeline")) {
+                let total = total.map(|x| x * timescale / 1_000_000);
+                let mut time = 0;
+
+                for s in timeline.children().filter(|x| x.is_element() && x.tag_name().name() == "S") {
+                    let number = |name: &str| s.attribute(name).and_then(|x| x.parse::<i64>().ok());
+
+                    time = number("t").map_or(time, |x| x as u64);
+                    let duration = number("d").ok_or("S element without duration")? as u64;
+
+                    // negative repeat means until the end of the period
+                    let repeat = match number("r").unwrap_or(0) {
+                        x if x < 0 => total.ok_or("Open ended repeat without total duration")?
+                            .saturating_sub(time)
+                            .div_ceil(duration.max(1))
+                            .saturating_sub(1),
+                        x => x as u64,
+                    };
+
+                    for _ in 0..=repeat {
+                        times.push((time, duration));
+                        time += duration;
+                    }
+                }
+            } else {
+                let duration = attribute(&templates, "duration").and_then(|x| x.parse::<u64>().ok())
+                    .ok_or("SegmentTemplate without duration or timeline")?;
+                let total = total.ok_or("Manifest without total duration")? * timescale / 1_000_000;
+
+                let mut time = 0;
+                while time < total {
+                    times.push((time, duration.min(total - time)));
+                    time += duration;
+                }
+            }
+
+            for (i, (time, duration)) in times.into_iter().enumerate() {
+                let path = fill_template(&media, representation_id, start_number + i as u64, time, bandwidth);
+                manifest.segments.push(Segment {
+                    path: resolve(&base, &path)?,
+                    duration: duration * 1_000_000 / timescale,
+                });
+            }
+        }
+
+        if manifest.segments.is_empty() {
+            return Err("Manifest has no segments".into());
+        }
+
+        Ok(manifest)
+    }
+
+    /// Range of segments overlapping the region and start time of the first one
+    pub fn segments_for(&self, region: Span) -> Option<(u64, Range<usize>)> {
+        let mut start = 0;
+        let mut first: Option<(usize, u64)> = None;
+        let mut last = 0;
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            let end = start + segment.duration;
+
+            if end > region.0 && start < region.1.max(region.0 + 1) {
+                first.get_or_insert((i, start));
+                last = i;
+            }
+
+            start = end;
+        }
+
+        first.map(|(i, offset)| (offset, i..last + 1))
+    }
+
+    /// Extension of the media in the segments
+    pub fn extension(&self) -> String {
+        match &self.init {
+            Some(init) if init.extension().is_some_and(|x| x == "webm") => "webm".into(),
+            // fragmented mp4 segments are only playable together with the init segment
+            Some(_) => "mp4".into(),
+            None => self.segments[0].path.extension().unwrap_or_default().to_string_lossy().to_string(),
+        }
+    }
+
+    /// Path that ffmpeg can read as one file, the concat protocol joins the files byte by byte
+    /// which works for both mpegts and fragmented files with init segment in front
+    fn concat_input(&self, range: Range<usize>) -> Result<String, String> {
+        let files: Vec<String> = self.init.iter()
+            .chain(self.segments[range].iter().map(|x| &x.path))
+            .map(|x| x.to_string_lossy().to_string())
+            .collect();
+
+        if let Some(file) = files.iter().find(|x| x.contains('|')) {
+            return Err(format!("Segment path {:?} contains '|' which is not supported", file));
+        }
+
+        Ok(format!("concat:{}", files.join("|")))
+    }
+}
+
+impl VideoFile {
+    /// Extract segment from local manifest, only the segments covering the region are read and
+    /// keyframes are searched across all of them
+    ///
+    /// If both source and dest are HLS playlists then only the segments at the edges of the region
+    /// are cut, the rest are copied as they are
+    pub fn extract_from_manifest(&self, region: Span, force_align_keyframes: bool, dest: &str) -> crate::ExitResult {
+        let manifest = Manifest::load(&self.path).map_err(|err| {
+            self.report.error(err);
+            1
+        })?;
+
+        let Some((offset, range)) = manifest.segments_for(region) else {
+            self.report.error("Region is outside of the manifest");
+            return Err(1);
+        };
+
+        eprintln!("Reading {} of {} segments from the manifest", range.len(), manifest.segments.len());
+
+        if super::hls::is_playlist(Path::new(dest)) {
+            return self.rewrite_playlist(&manifest, offset, range, region, force_align_keyframes, dest);
+        }
+
+        let input = manifest.concat_input(range).map_err(|err| {
+            self.report.error(err);
+            1
+        })?;
+
+        self.with_path(input).extract_segment(
+            (region.0.saturating_sub(offset), region.1.saturating_sub(offset)),
+            force_align_keyframes,
+            dest,
+        ).map(|_| ())
+    }
+
+    /// Write new playlist for the region where only the first and last segment are cut
+    ///
+    /// Segments that were written are removed again if any of them fails, so no partial output
+    /// is left behind
+    fn rewrite_playlist(&self, manifest: &Manifest, offset: u64, range: Range<usize>, region: Span, force_align_keyframes: bool, dest: &str) -> crate::ExitResult {
+        if manifest.init.is_some() {
+            self.report.error("Only playlists with mpegts segments can be rewritten, output into a single file instead");
+            return Err(1);
+        }
+
+        let dest_path = Path::new(dest);
+        let dir = dest_path.parent().unwrap_or(Path::new(""));
+        let stem = dest_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+        let segments = &manifest.segments[range];
+        let names: Vec<String> = (0..segments.len()).map(|i| format!("{}{:05}.ts", stem, i)).collect();
+        let outputs: Vec<String> = names.iter().map(|x| dir.join(x).to_string_lossy().to_string()).collect();
+
+        // check everything before writing anything
+        self.check_output(dest, &[&self.path])?;
+        for (out, segment) in outputs.iter().zip(segments) {
+            self.check_output(out, &[&self.path, &segment.path])?;
+        }
+
+        let mut playlist = vec![];
+        let mut written: Vec<&str> = vec![];
+
+        let mut write_segments = || -> crate::ExitResult {
+            // the edge segments restart timestamps from zero
+            let mut discontinuity = false;
+            let mut segment_start = offset;
+
+            for (i, segment) in segments.iter().enumerate() {
+                self.cancel.check()?;
+
+                let segment_end = segment_start + segment.duration;
+                let cut = (
+                    region.0.max(segment_start) - segment_start,
+                    region.1.min(segment_end) - segment_start,
+                );
+
+                let out = &outputs[i];
+
+                let duration = if cut == (0, segment.duration) {
+                    self.with_path(&segment.path).write_output(out, |path| {
+                        if self.dry_run {
+                            self.plan.push(Step::CopyFile { from: segment.path.to_string_lossy().to_string(), to: path.into() });
+                            return Ok(());
+                        }
+
+                        std::fs::copy(&segment.path, path).map(|_| ()).map_err(|err| {
+                            self.report.error(format!("Could not copy segment {:?}: {}", segment.path, err));
+                            1
+                        })
+                    })?;
+
+                    if discontinuity {
+                        playlist.push("#EXT-X-DISCONTINUITY".to_string());
+                        discontinuity = false;
+                    }
+
+                    segment.duration
+                } else {
+                    // aligning to keyframes can write more than the cut
+                    let span = self.with_path(&segment.path).extract_segment(cut, force_align_keyframes, out)?;
+
+                    if i > 0 {
+                        playlist.push("#EXT-X-DISCONTINUITY".to_string());
+                    }
+                    discontinuity = true;
+
+                    span.1.min(segment.duration).saturating_sub(span.0)
+                };
+
+                if !self.dry_run {
+                    written.push(out);
+                }
+
+                playlist.push(format!("#EXTINF:{:.6},", duration as f64 / 1_000_000.0));
+                playlist.push(names[i].clone());
+
+                segment_start = segment_end;
+            }
+
+            Ok(())
+        };
+
+        if let Err(code) = write_segments() {
+            for out in written {
+                let _ = std::fs::remove_file(out);
+            }
+
+            return Err(code);
+        }
+
+        let target_duration = manifest.segments.iter().map(|x| x.duration).max().unwrap_or(0).div_ceil(1_000_000);
+        let content = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-PLAYLIST-TYPE:VOD\n{}\n#EXT-X-ENDLIST\n",
+            target_duration,
+            playlist.join("\n"),
+        );
+
+        if self.dry_run {
+            self.plan.push(Step::WriteFile { path: dest.into(), content });
+            return Ok(());
+        }
+
+        let output = util::AtomicOutput::new(dest);
+        if let Err(err) = std::fs::write(output.path(), content) {
+            self.report.error(format!("Could not write playlist {:?}: {}", dest, err));
+            return Err(1);
+        }
+
+        self.commit_output(output)?;
+
+        self.report.output(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concat_input() {
+        let manifest = Manifest {
+            init: Some(PathBuf::from("/hls/init.mp4")),
+            segments: ["seg0.m4s", "seg1.m4s", "seg2.m4s"].iter()
+                .map(|x| Segment { path: Path::new("/hls").join(x), duration: 1_000_000 })
+                .collect(),
+        };
+
+        assert_eq!(
+            manifest.concat_input(1..3),
+            Ok("concat:/hls/init.mp4|/hls/seg1.m4s|/hls/seg2.m4s".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_dash_template() {
+        let content = r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" mediaPresentationDuration="PT10S">
+  <Period>
+    <AdaptationSet contentType="video">
+      <SegmentTemplate timescale="1000" initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/$Number%03d$.m4s" startNumber="1">
+        <SegmentTimeline>
+          <S t="0" d="4000" r="1"/>
+          <S d="2000"/>
+        </SegmentTimeline>
+      </SegmentTemplate>
+      <Representation id="low" bandwidth="100000"/>
+      <Representation id="high" bandwidth="500000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+        let manifest = Manifest::parse_dash(content, Path::new("/dash")).unwrap();
+        assert_eq!(manifest.init, Some(PathBuf::from("/dash/high/init.mp4")));
+        assert_eq!(manifest.segments, vec![
+            Segment { path: PathBuf::from("/dash/high/001.m4s"), duration: 4_000_000 },
+            Segment { path: PathBuf::from("/dash/high/002.m4s"), duration: 4_000_000 },
+            Segment { path: PathBuf::from("/dash/high/003.m4s"), duration: 2_000_000 },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_dash_list() {
+        let content = r#"<MPD mediaPresentationDuration="PT6S">
+  <BaseURL>media/</BaseURL>
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <Representation id="1" bandwidth="1">
+        <SegmentList timescale="90000" duration="270000">
+          <Initialization sourceURL="init.mp4"/>
+          <SegmentURL media="a.m4s"/>
+          <SegmentURL media="b.m4s"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4">
+      <Representation id="2" bandwidth="1"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+        // separate audio is not supported
+        assert!(Manifest::parse_dash(content, Path::new("/dash")).is_err());
+
+        let content = content.replace(r#"mimeType="audio/mp4""#, r#"mimeType="text/vtt""#);
+        let manifest = Manifest::parse_dash(&content, Path::new("/dash")).unwrap();
+        assert_eq!(manifest.init, Some(PathBuf::from("/dash/media/init.mp4")));
+        assert_eq!(manifest.segments, vec![
+            Segment { path: PathBuf::from("/dash/media/a.m4s"), duration: 3_000_000 },
+            Segment { path: PathBuf::from("/dash/media/b.m4s"), duration: 3_000_000 },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_iso_duration() {
+        assert_eq!(parse_iso_duration("PT10S"), Some(10_000_000));
+        assert_eq!(parse_iso_duration("PT1H2M3.5S"), Some(3_723_500_000));
+        assert_eq!(parse_iso_duration("P1D"), Some(86_400_000_000));
+        assert_eq!(parse_iso_duration("10S"), None);
+    }
+
+    #[test]
+    fn test_fill_template() {
+        assert_eq!(fill_template("$RepresentationID$/$Number%05d$.m4s", "v1", 7, 0, 0), "v1/00007.m4s");
+        assert_eq!(fill_template("seg-$Time$-$Bandwidth$.m4s$$", "", 0, 9000, 128), "seg-9000-128.m4s$");
+    }
+}
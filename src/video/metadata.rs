@@ -0,0 +1,182 @@
+use std::sync::LazyLock;
+
+use super::VideoFile;
+use crate::util::{civil_from_days, days_from_civil};
+
+/// Global tags that describe the file itself and are written by the muxer
+const MUXER_TAGS: &[&str] = &["encoder", "major_brand", "minor_version", "compatible_brands"];
+
+/// How tags are written into outputs
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MetadataOptions {
+    /// Do not copy any tags from the source
+    pub strip: bool,
+
+    /// Global tags to set as key and value
+    pub set: Vec<(String, String)>,
+
+    /// Stream tags to set as ffmpeg stream specifier of the output stream, key and value
+    pub streams: Vec<(String, String, String)>,
+}
+
+/// Parse `key=value`
+pub fn parse_tag(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.into(), value.into())),
+        _ => Err("Expected key=value".into()),
+    }
+}
+
+/// Parse `stream:key=value` where stream is ffmpeg stream specifier like `a:0`
+pub fn parse_stream_tag(input: &str) -> Result<(String, String, String), String> {
+    let (target, value) = parse_tag(input)?;
+
+    match target.rsplit_once(':') {
+        Some((stream, key)) if !stream.is_empty() && !key.is_empty() => Ok((stream.into(), key.into(), value)),
+        _ => Err("Expected stream:key=value, like a:0:language=eng".into()),
+    }
+}
+
+/// Move ISO 8601 time in UTC like `2024-01-01T10:00:00.000000Z` by offset micros, `None` if it
+/// is in some other format
+pub fn shift_creation_time(value: &str, offset: u64) -> Option<String> {
+    static RE: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(r"^(\d{4})-(\d{2})-(\d{2})[T ](\d{2}):(\d{2}):(\d{2})(?:\.(\d{1,6}))?Z?$").unwrap()
+    });
+
+    let captures = RE.captures(value)?;
+    let number = |i: usize| captures.get(i).map_or(Some(0), |x| x.as_str().parse::<i64>().ok());
+
+    // fraction is scaled to micros, ".5" is half a second
+    let fraction = captures.get(7).map_or(0, |x| {
+        x.as_str().parse::<i64>().unwrap_or(0) * 10_i64.pow(6 - x.as_str().len() as u32)
+    });
+
+    let days = days_from_civil(number(1)?, number(2)? as u32, number(3)? as u32);
+    let secs = days * 86_400 + number(4)? * 3600 + number(5)? * 60 + number(6)?;
+    let micros = secs * 1_000_000 + fraction + offset as i64;
+
+    let (secs, fraction) = (micros.div_euclid(1_000_000), micros.rem_euclid(1_000_000));
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year, month, day, time / 3600, (time / 60) % 60, time % 60, fraction,
+    ))
+}
+
+/// Arguments that move creation time of each stream from ffprobe `streams` by offset
+///
+/// Outputs do not always have all the streams or in the same order, so streams are picked by
+/// their original value, which ffmpeg copies to the output streams, not by index
+pub fn stream_creation_time_args(streams: &[serde_json::Value], offset: u64) -> Vec<String> {
+    let mut args: Vec<String> = vec![];
+    let mut seen: Vec<&str> = vec![];
+
+    for value in streams.iter().filter_map(|x| x["tags"]["creation_time"].as_str()) {
+        if seen.contains(&value) {
+            continue;
+        }
+        seen.push(value);
+
+        if let Some(shifted) = shift_creation_time(value, offset) {
+            args.extend([format!("-metadata:s:m:creation_time:{}", value), format!("creation_time={}", shifted)]);
+        }
+    }
+
+    args
+}
+
+impl VideoFile {
+    /// Arguments for tags of output that starts offset micros into this file
+    ///
+    /// Global tags are copied explicitly as the concat demuxer and intermediate files would lose
+    /// them, creation time of the file and its streams is moved by the offset
+    pub(super) fn metadata_args(&self, offset: u64) -> Result<Vec<String>, u8> {
+        let mut args: Vec<String> = vec![];
+        let options = &self.metadata;
+
+        if options.strip {
+            args.extend(["-map_metadata".into(), "-1".into()]);
+        } else {
+            let data = self.probe_json(&["-show_entries", "format_tags:stream_tags=creation_time"])
+                .map_err(|x| self.report.fail(x))?;
+
+            let tags = data["format"]["tags"].as_object().cloned().unwrap_or_default();
+            for (key, value) in &tags {
+                let Some(value) = value.as_str() else {
+                    continue;
+                };
+
+                if MUXER_TAGS.contains(&key.as_str()) {
+                    continue;
+                }
+
+                let value = match key.as_str() {
+                    "creation_time" => shift_creation_time(value, offset).unwrap_or(value.into()),
+                    _ => value.into(),
+                };
+
+                args.extend(["-metadata".into(), format!("{}={}", key, value)]);
+            }
+
+            args.extend(stream_creation_time_args(&data["streams"].as_array().cloned().unwrap_or_default(), offset));
+        }
+
+        for (key, value) in &options.set {
+            args.extend(["-metadata".into(), format!("{}={}", key, value)]);
+        }
+
+        for (stream, key, value) in &options.streams {
+            args.extend([format!("-metadata:s:{}", stream), format!("{}={}", key, value)]);
+        }
+
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(parse_tag("title=My video"), Ok(("title".into(), "My video".into())));
+        assert_eq!(parse_tag("comment="), Ok(("comment".into(), "".into())));
+        assert!(parse_tag("title").is_err());
+
+        assert_eq!(parse_stream_tag("a:0:language=eng"), Ok(("a:0".into(), "language".into(), "eng".into())));
+        assert_eq!(parse_stream_tag("1:title=x=y"), Ok(("1".into(), "title".into(), "x=y".into())));
+        assert!(parse_stream_tag("language=eng").is_err());
+    }
+
+    #[test]
+    fn test_shift_creation_time() {
+        assert_eq!(
+            shift_creation_time("2024-02-28T23:59:30.000000Z", 45_500_000).as_deref(),
+            Some("2024-02-29T00:00:15.500000Z")
+        );
+        assert_eq!(
+            shift_creation_time("2023-12-31 23:59:59", 1_000_000).as_deref(),
+            Some("2024-01-01T00:00:00.000000Z")
+        );
+        assert_eq!(shift_creation_time("yesterday", 0), None);
+    }
+
+    #[test]
+    fn test_stream_creation_time_args() {
+        let streams = [
+            serde_json::json!({ "tags": { "creation_time": "2024-01-01T10:00:00.000000Z" } }),
+            serde_json::json!({ "tags": { "creation_time": "2024-01-01T09:00:00.000000Z" } }),
+            serde_json::json!({}),
+            serde_json::json!({ "tags": { "creation_time": "2024-01-01T10:00:00.000000Z" } }),
+            serde_json::json!({ "tags": { "creation_time": "yesterday" } }),
+        ];
+
+        assert_eq!(stream_creation_time_args(&streams, 1_000_000), [
+            "-metadata:s:m:creation_time:2024-01-01T10:00:00.000000Z", "creation_time=2024-01-01T10:00:01.000000Z",
+            "-metadata:s:m:creation_time:2024-01-01T09:00:00.000000Z", "creation_time=2024-01-01T09:00:01.000000Z",
+        ]);
+    }
+}
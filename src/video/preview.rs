@@ -0,0 +1,267 @@
+use std::path::Path;
+
+use super::{Span, VideoFile};
+use crate::{plan::Step, util::{self, command_extensions::*}};
+
+/// Formats of animated previews
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Gif,
+    WebP,
+    Apng,
+}
+
+impl AnimationFormat {
+    /// Guess the format from file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+
+        match ext.as_str() {
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::WebP),
+            "png" | "apng" => Some(Self::Apng),
+            _ => None,
+        }
+    }
+}
+
+/// Evenly spaced times in the region, each one is in the middle of its part so the first and last
+/// frame (often black or a transition) are avoided
+pub fn thumbnail_times(region: Span, count: usize) -> Vec<u64> {
+    let length = region.1.saturating_sub(region.0);
+
+    (0..count as u64)
+        .map(|i| region.0 + (2 * i + 1) * length / (2 * count as u64))
+        .collect()
+}
+
+/// Format time in micros as WebVTT timestamp `HH:MM:SS.mmm`
+fn format_vtt_time(micros: u64) -> String {
+    let millis = micros / 1000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        millis % 1000,
+    )
+}
+
+/// WebVTT track pointing each part of the region to its tile in the sprite sheet, times are from
+/// the source so the track can be used with the original video
+pub fn sprite_vtt(region: Span, count: usize, columns: usize, tile: (u32, u32), sprite: &str) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    let length = region.1.saturating_sub(region.0);
+
+    for i in 0..count {
+        let start = region.0 + i as u64 * length / count as u64;
+        let end = region.0 + (i as u64 + 1) * length / count as u64;
+        let (x, y) = ((i % columns) as u32 * tile.0, (i / columns) as u32 * tile.1);
+
+        vtt += &format!(
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            format_vtt_time(start), format_vtt_time(end), sprite, x, y, tile.0, tile.1,
+        );
+    }
+
+    vtt
+}
+
+impl VideoFile {
+    /// Get width and height of the first video stream
+    pub fn get_resolution(&self) -> Result<(u32, u32), (String, u8)> {
+        let data = self.probe_json(&["-select_streams", "v:0", "-show_entries", "stream=width,height"])?;
+
+        let stream = &data["streams"][0];
+        match (stream["width"].as_u64(), stream["height"].as_u64()) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => Ok((width as u32, height as u32)),
+            _ => Err((format!("Could not get resolution of {:?}", self.path), 1)),
+        }
+    }
+
+    /// Height matching the width while keeping aspect ratio, rounded to even number as most
+    /// encoders require
+    fn scaled_height(&self, width: u32) -> Result<u32, u8> {
+        let (source_width, source_height) = self.get_resolution().map_err(|x| self.report.fail(x))?;
+
+        let height = (width as u64 * source_height as u64 / source_width as u64) as u32;
+        Ok(height.div_ceil(2).max(1) * 2)
+    }
+
+    /// Command reading the region, seeking on the input is fast and exact as everything is
+    /// decoded anyway
+    fn decode_region_cmd(&self, region: Span) -> Command {
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.args([
+            "-ss".into(), format!("{}us", region.0),
+            "-t".into(), format!("{}us", region.1.saturating_sub(region.0)),
+        ]);
+        cmd.arg("-i").arg(&self.path);
+
+        cmd
+    }
+
+    /// Write animated preview of the region, format is chosen by extension of dest
+    pub fn animation(&self, region: Span, fps: u32, width: u32, dest: &str) -> crate::ExitResult {
+        let Some(format) = AnimationFormat::from_path(Path::new(dest)) else {
+            self.report.error("Unknown animation format, supported extensions are gif, webp, png and apng");
+            return Err(1);
+        };
+
+        if fps == 0 || width == 0 {
+            self.report.error("Frame rate and width cannot be zero");
+            return Err(1);
+        }
+
+        let filters = format!("fps={},scale={}:-2:flags=lanczos", fps, width);
+
+        self.write_output(dest, |dest| {
+            let mut cmd = self.decode_region_cmd(region);
+            cmd.arg("-an");
+
+            match format {
+                AnimationFormat::Gif => {
+                    // generate palette from the clip itself, default palette looks awful
+                    cmd.args([
+                        "-filter_complex".into(),
+                        format!("{},split[a][b];[a]palettegen=stats_mode=diff[p];[b][p]paletteuse=dither=bayer:bayer_scale=5:diff_mode=rectangle", filters),
+                        "-loop".into(), "0".into(),
+                        "-f".into(), "gif".into(),
+                    ]);
+                },
+                AnimationFormat::WebP => {
+                    cmd.args([
+                        "-vf".into(), filters,
+                        "-c:v".into(), "libwebp".into(),
+                        "-q:v".into(), "75".into(),
+                        "-loop".into(), "0".into(),
+                        "-f".into(), "webp".into(),
+                    ]);
+                },
+                AnimationFormat::Apng => {
+                    cmd.args([
+                        "-vf".into(), filters,
+                        "-plays".into(), "0".into(),
+                        "-f".into(), "apng".into(),
+                    ]);
+                },
+            }
+
+            cmd.arg(dest);
+            self.run_ffmpeg(&cmd, dest, region.1.saturating_sub(region.0))
+        })
+    }
+
+    /// Write evenly spaced thumbnails of the region, file names are generated with `dest_for`
+    /// from index and time of the thumbnail
+    pub fn thumbnails(&self, region: Span, count: usize, width: u32, dest_for: impl Fn(usize, u64) -> Result<String, u8>) -> crate::ExitResult {
+        if count == 0 || width == 0 {
+            self.report.error("Number of thumbnails and width cannot be zero");
+            return Err(1);
+        }
+
+        for (i, time) in thumbnail_times(region, count).into_iter().enumerate() {
+            self.cancel.check()?;
+
+            self.write_output(&dest_for(i, time)?, |dest| {
+                let mut cmd = self.ffmpeg_cmd();
+                cmd.args(["-ss".into(), format!("{}us", time)]);
+                cmd.arg("-i").arg(&self.path);
+                cmd.args([
+                    "-an".into(),
+                    "-frames:v".into(), "1".into(),
+                    "-vf".into(), format!("scale={}:-2", width),
+                    // keep the format, temporary output would be treated as image sequence
+                    "-update".into(), "1".into(),
+                ]);
+                cmd.arg(dest);
+
+                self.run_ffmpeg(&cmd, dest, 0)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Write thumbnails of the region tiled into one image, with WebVTT thumbnail track next to
+    /// it (same name with .vtt extension)
+    pub fn sprite_sheet(&self, region: Span, count: usize, columns: usize, width: u32, dest: &str) -> crate::ExitResult {
+        if count == 0 || columns == 0 || width == 0 {
+            self.report.error("Number of thumbnails, columns and width cannot be zero");
+            return Err(1);
+        }
+
+        let vtt_path = Path::new(dest).with_extension("vtt").to_string_lossy().to_string();
+        self.check_output(&vtt_path, &[&self.path])?;
+
+        let height = self.scaled_height(width)?;
+        let columns = columns.min(count);
+        let rows = count.div_ceil(columns);
+        let length = region.1.saturating_sub(region.0);
+
+        self.write_output(dest, |dest| {
+            let mut cmd = self.decode_region_cmd(region);
+            cmd.args([
+                "-an".into(),
+                "-vf".into(),
+                format!(
+                    "fps={}/{},scale={}:{},tile={}x{}",
+                    count * 1_000_000, length.max(1), width, height, columns, rows,
+                ),
+                "-frames:v".into(), "1".into(),
+                "-update".into(), "1".into(),
+            ]);
+            cmd.arg(dest);
+
+            self.run_ffmpeg(&cmd, dest, length)
+        })?;
+
+        // the track references the sprite relative to itself
+        let sprite_name = Path::new(dest).file_name().unwrap_or_default().to_string_lossy().to_string();
+        let vtt = sprite_vtt(region, count, columns, (width, height), &sprite_name);
+
+        if self.dry_run {
+            self.plan.push(Step::WriteFile { path: vtt_path, content: vtt });
+            return Ok(());
+        }
+
+        let output = util::AtomicOutput::new(&vtt_path);
+        if let Err(err) = std::fs::write(output.path(), vtt) {
+            self.report.error(format!("Could not write thumbnail track {:?}: {}", vtt_path, err));
+            return Err(1);
+        }
+
+        self.commit_output(output)?;
+
+        self.report.output(&vtt_path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail_times() {
+        assert_eq!(thumbnail_times((0, 10_000_000), 5), vec![1_000_000, 3_000_000, 5_000_000, 7_000_000, 9_000_000]);
+        assert_eq!(thumbnail_times((2_000_000, 4_000_000), 1), vec![3_000_000]);
+    }
+
+    #[test]
+    fn test_sprite_vtt() {
+        let vtt = sprite_vtt((60_000_000, 66_000_000), 3, 2, (160, 90), "sprite.jpg");
+
+        assert_eq!(vtt, "WEBVTT
+
+00:01:00.000 --> 00:01:02.000
+sprite.jpg#xywh=0,0,160,90
+
+00:01:02.000 --> 00:01:04.000
+sprite.jpg#xywh=160,0,160,90
+
+00:01:04.000 --> 00:01:06.000
+sprite.jpg#xywh=0,90,160,90
+");
+    }
+}
@@ -0,0 +1,291 @@
+use std::path::Path;
+
+use super::{StreamInfo, VideoFile};
+
+/// Container formats that rcut knows how to remux between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Mov,
+    Mkv,
+    WebM,
+    Ts,
+}
+
+impl Container {
+    /// Guess the container from file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+
+        match ext.as_str() {
+            "mp4" | "m4v" | "m4a" => Some(Self::Mp4),
+            "mov" => Some(Self::Mov),
+            "mkv" | "mka" => Some(Self::Mkv),
+            "webm" => Some(Self::WebM),
+            "ts" | "mts" | "m2ts" => Some(Self::Ts),
+            _ => None,
+        }
+    }
+
+    /// Check if the codec can be stored in this container without transcoding
+    pub fn allows(&self, stream: &StreamInfo) -> bool {
+        let codec = stream.codec_name.as_str();
+
+        match (self, stream.codec_type.as_str()) {
+            // matroska can hold pretty much anything
+            (Self::Mkv, _) => true,
+
+            (Self::Mp4 | Self::Mov, "video") => matches!(codec, "h264" | "hevc" | "av1" | "vp9" | "mpeg4" | "mpeg2video" | "mjpeg")
+                || (*self == Self::Mov && matches!(codec, "prores" | "dnxhd")),
+            (Self::Mp4 | Self::Mov, "audio") => matches!(codec, "aac" | "mp3" | "ac3" | "eac3" | "opus" | "flac" | "alac")
+                || (*self == Self::Mov && codec.starts_with("pcm_")),
+            (Self::Mp4 | Self::Mov, "subtitle") => codec == "mov_text",
+
+            (Self::WebM, "video") => matches!(codec, "vp8" | "vp9" | "av1"),
+            (Self::WebM, "audio") => matches!(codec, "opus" | "vorbis"),
+            (Self::WebM, "subtitle") => codec == "webvtt",
+
+            (Self::Ts, "video") => matches!(codec, "h264" | "hevc" | "mpeg2video" | "mpeg4"),
+            (Self::Ts, "audio") => matches!(codec, "aac" | "mp3" | "mp2" | "ac3" | "eac3" | "opus"),
+            (Self::Ts, "subtitle") => codec == "dvb_subtitle",
+
+            _ => false,
+        }
+    }
+
+    /// Codec text subtitles can be converted into, if the container supports text subtitles
+    fn text_subtitle_codec(&self) -> Option<&'static str> {
+        match self {
+            Self::Mp4 | Self::Mov => Some("mov_text"),
+            Self::Mkv => Some("subrip"),
+            Self::WebM => Some("webvtt"),
+            Self::Ts => None,
+        }
+    }
+
+    /// Arguments to make the output start at zero, timestamps are moved so the first one is zero
+    /// which also gets rid of negative decoding times of b-frames
+    fn normalize_timestamps_args(&self) -> Vec<&'static str> {
+        let mut args = vec!["-avoid_negative_ts", "make_zero"];
+
+        match self {
+            // edit list hides the delay of b-frames so presentation starts at zero too
+            Self::Mp4 | Self::Mov => args.extend(["-use_editlist", "1"]),
+            // no edit lists, cues and block timestamps are relative to the first cluster
+            Self::Mkv | Self::WebM => {},
+            // muxer still adds its own delay that players expect and subtract
+            Self::Ts => {},
+        }
+
+        args
+    }
+
+    /// H.264/HEVC are stored in length prefixed format in these, and in Annex B in mpegts
+    fn uses_length_prefixed_nal(&self) -> bool {
+        !matches!(self, Self::Ts)
+    }
+}
+
+/// Arguments for ffmpeg to remux streams from source container into dest container
+///
+/// Every stream is mapped explicitly, streams that do not fit into the new container are either
+/// converted (text subtitles), dropped with a warning (data, attachments, bitmap subtitles) or
+/// produce an error (video and audio), bitstream filters required by the container change are
+/// added for each copied stream, warnings are passed to `warn`
+pub fn remux_args(source: Container, dest: Container, streams: &[StreamInfo], copy_video: bool, warn: impl Fn(String)) -> Result<Vec<String>, String> {
+    let mut args: Vec<String> = vec![];
+    let mut output_index = 0;
+
+    for stream in streams {
+        let allowed = dest.allows(stream);
+        let is_text_subtitle = matches!(stream.codec_name.as_str(), "subrip" | "ass" | "ssa" | "mov_text" | "webvtt" | "text");
+
+        // transcoded video gets encoder that fits the container
+        let transcoded = stream.codec_type == "video" && !copy_video;
+
+        match stream.codec_type.as_str() {
+            "video" | "audio" if allowed || transcoded => {},
+            "video" | "audio" => {
+                return Err(format!(
+                    "Stream #{} ({} {}) cannot be stored in {:?} container without transcoding",
+                    stream.index, stream.codec_type, stream.codec_name, dest
+                ));
+            },
+            "subtitle" if allowed => {},
+            "subtitle" if is_text_subtitle && dest.text_subtitle_codec().is_some() => {},
+            _ => {
+                warn(format!(
+                    "dropping stream #{} ({} {}) as it is not supported in {:?} container",
+                    stream.index, stream.codec_type, stream.codec_name, dest
+                ));
+                continue;
+            },
+        }
+
+        args.extend(["-map".into(), format!("0:{}", stream.index)]);
+
+        if stream.codec_type == "subtitle" && !allowed {
+            args.extend([format!("-c:{}", output_index), dest.text_subtitle_codec().unwrap().into()]);
+        }
+
+        let bsf = match (stream.codec_name.as_str(), stream.codec_type.as_str()) {
+            ("h264", "video") if copy_video && source.uses_length_prefixed_nal() && !dest.uses_length_prefixed_nal() => Some("h264_mp4toannexb"),
+            ("hevc", "video") if copy_video && source.uses_length_prefixed_nal() && !dest.uses_length_prefixed_nal() => Some("hevc_mp4toannexb"),
+            // aac in mpegts has ADTS headers that mp4 does not allow
+            ("aac", "audio") if source == Container::Ts && matches!(dest, Container::Mp4 | Container::Mov) => Some("aac_adtstoasc"),
+            _ => None,
+        };
+
+        if let Some(bsf) = bsf {
+            args.extend([format!("-bsf:{}", output_index), bsf.into()]);
+        }
+
+        output_index += 1;
+    }
+
+    if matches!(dest, Container::Mp4 | Container::Mov) {
+        // move index to the start so the file can be played while downloading
+        args.extend(["-movflags".into(), "+faststart".into()]);
+    }
+
+    Ok(args)
+}
+
+impl VideoFile {
+    /// Arguments needed to remux from source to dest if their containers differ, `copy_video`
+    /// should be false if video is being transcoded
+    ///
+    /// Streams are always probed from this file as source may be intermediate file that does not
+    /// exist yet
+    pub(super) fn remux_args_for(&self, source: &str, dest: &str, copy_video: bool) -> Result<Vec<String>, u8> {
+        let (Some(source), Some(dest)) = (Container::from_path(Path::new(source)), Container::from_path(Path::new(dest))) else {
+            // let ffmpeg decide what to do with unknown formats
+            return Ok(vec![]);
+        };
+
+        if source == dest {
+            return Ok(vec![]);
+        }
+
+        let streams = self.get_streams().map_err(|x| self.report.fail(x))?;
+
+        remux_args(source, dest, &streams, copy_video, |x| self.report.warning(x)).map_err(|err| {
+            self.report.error(err);
+            1
+        })
+    }
+
+    /// Arguments for timestamps of output written to dest, original timestamps of the source are
+    /// kept if `keep` is set, otherwise the output starts at zero
+    pub(super) fn timestamp_args(&self, dest: &str, keep: bool) -> Vec<String> {
+        let args = if keep {
+            // outputs cannot have negative timestamps, they are shifted only if needed
+            vec!["-copyts", "-avoid_negative_ts", "make_non_negative"]
+        } else {
+            Container::from_path(Path::new(dest))
+                .map_or(vec!["-avoid_negative_ts", "make_zero"], |x| x.normalize_timestamps_args())
+        };
+
+        args.into_iter().map(String::from).collect()
+    }
+
+    /// Copy all streams into a different container
+    pub fn remux(&self, dest: &str) -> crate::ExitResult {
+        let containers = (Container::from_path(&self.path), Container::from_path(Path::new(dest)));
+        let (Some(source), Some(dest_container)) = containers else {
+            self.report.error("Unknown container format, supported extensions are mp4, m4v, mov, mkv, webm and ts");
+            return Err(1);
+        };
+
+        let streams = self.get_streams().map_err(|x| self.report.fail(x))?;
+
+        let args = remux_args(source, dest_container, &streams, true, |x| self.report.warning(x)).map_err(|err| {
+            self.report.error(err);
+            1
+        })?;
+
+        self.write_output(dest, |dest| {
+            let mut cmd = self.ffmpeg_cmd();
+            cmd.arg("-i").arg(&self.path);
+            cmd.args(["-c", "copy"]);
+            cmd.args(&args);
+            cmd.arg(dest);
+
+            // whole file is copied
+            let length = self.get_duration().unwrap_or(0);
+            self.run_ffmpeg(&cmd, dest, length)
+        })?;
+
+        // the streams should be offset the same as in the source
+        let offset = if self.verify && !self.dry_run {
+            self.get_sync_report().map_err(|x| self.report.fail(x))?.av_offset.unwrap_or(0)
+        } else {
+            0
+        };
+
+        self.verify_output(dest, || self.get_duration().ok(), offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(index: usize, codec_type: &str, codec_name: &str) -> StreamInfo {
+        StreamInfo {
+            index,
+            codec_type: codec_type.into(),
+            codec_name: codec_name.into(),
+        }
+    }
+
+    #[test]
+    fn test_timestamp_args() {
+        let vfile = VideoFile::default();
+
+        assert_eq!(vfile.timestamp_args("a.mp4", false), ["-avoid_negative_ts", "make_zero", "-use_editlist", "1"]);
+        assert_eq!(vfile.timestamp_args("a.mkv", false), ["-avoid_negative_ts", "make_zero"]);
+        assert_eq!(vfile.timestamp_args("a.avi", false), ["-avoid_negative_ts", "make_zero"]);
+        assert_eq!(vfile.timestamp_args("a.mp4", true), ["-copyts", "-avoid_negative_ts", "make_non_negative"]);
+    }
+
+    #[test]
+    fn test_container_from_path() {
+        assert_eq!(Container::from_path(Path::new("a.MP4")), Some(Container::Mp4));
+        assert_eq!(Container::from_path(Path::new("/x/a.m2ts")), Some(Container::Ts));
+        assert_eq!(Container::from_path(Path::new("a.avi")), None);
+        assert_eq!(Container::from_path(Path::new("a")), None);
+    }
+
+    #[test]
+    fn test_remux_args() {
+        // ts to mp4 needs aac converted from ADTS
+        let streams = [stream(0, "video", "h264"), stream(1, "audio", "aac")];
+        assert_eq!(
+            remux_args(Container::Ts, Container::Mp4, &streams, true, |_| {}),
+            Ok(["-map", "0:0", "-map", "0:1", "-bsf:1", "aac_adtstoasc", "-movflags", "+faststart"].map(String::from).to_vec())
+        );
+
+        // mkv to ts needs annex b, unless the video is transcoded
+        let streams = [stream(0, "video", "hevc"), stream(1, "audio", "ac3")];
+        assert_eq!(
+            remux_args(Container::Mkv, Container::Ts, &streams, true, |_| {}),
+            Ok(["-map", "0:0", "-bsf:0", "hevc_mp4toannexb", "-map", "0:1"].map(String::from).to_vec())
+        );
+        assert_eq!(
+            remux_args(Container::Mkv, Container::Ts, &streams, false, |_| {}),
+            Ok(["-map", "0:0", "-map", "0:1"].map(String::from).to_vec())
+        );
+
+        // subtitles are converted, attachments are dropped
+        let streams = [stream(0, "video", "h264"), stream(1, "attachment", "ttf"), stream(2, "subtitle", "subrip")];
+        assert_eq!(
+            remux_args(Container::Mkv, Container::Mov, &streams, true, |_| {}),
+            Ok(["-map", "0:0", "-map", "0:2", "-c:1", "mov_text", "-movflags", "+faststart"].map(String::from).to_vec())
+        );
+
+        // audio that cannot be copied
+        let streams = [stream(0, "video", "vp9"), stream(1, "audio", "vorbis")];
+        assert!(remux_args(Container::Mkv, Container::Mp4, &streams, true, |_| {}).is_err());
+    }
+}
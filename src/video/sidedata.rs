@@ -0,0 +1,217 @@
+use super::VideoFile;
+
+/// HDR10 mastering display in units x265 uses, 0.00002 for chromaticity and 0.0001 cd/m2 for
+/// luminance
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MasteringDisplay {
+    pub red: (u64, u64),
+    pub green: (u64, u64),
+    pub blue: (u64, u64),
+    pub white_point: (u64, u64),
+
+    /// Max and min luminance
+    pub luminance: (u64, u64),
+}
+
+/// Properties of the video that are lost when it is re-encoded unless they are given to the
+/// encoder, so re-encoded parts look the same as the copied ones
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VideoProps {
+    /// Counter-clockwise rotation from the display matrix in degrees
+    pub rotation: Option<i64>,
+
+    pub color_primaries: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_space: Option<String>,
+    pub color_range: Option<String>,
+
+    pub mastering_display: Option<MasteringDisplay>,
+
+    /// Max content and max frame-average light level in cd/m2
+    pub light_level: Option<(u64, u64)>,
+}
+
+/// Parse fraction like `35400/50000` from ffprobe and scale it
+fn fraction(value: &serde_json::Value, scale: u64) -> Option<u64> {
+    let (num, den) = value.as_str()?.split_once('/')?;
+    let (num, den) = (num.parse::<u64>().ok()?, den.parse::<u64>().ok()?);
+
+    (den > 0).then(|| num * scale / den)
+}
+
+impl VideoProps {
+    /// Read color properties of a stream from ffprobe, unknown values are left out
+    pub fn from_stream(stream: &serde_json::Value) -> Self {
+        let color = |key: &str| stream[key].as_str()
+            .filter(|x| !x.is_empty() && *x != "unknown")
+            .map(String::from);
+
+        Self {
+            color_primaries: color("color_primaries"),
+            color_transfer: color("color_transfer"),
+            color_space: color("color_space"),
+            color_range: color("color_range"),
+            ..Default::default()
+        }
+    }
+
+    /// Add side data from stream or frame `side_data_list`, first one found wins
+    pub fn add_side_data(&mut self, side_data: &[serde_json::Value]) {
+        for data in side_data {
+            match data["side_data_type"].as_str().unwrap_or_default() {
+                "Display Matrix" => {
+                    self.rotation = self.rotation.or(data["rotation"].as_i64()).filter(|x| *x != 0);
+                },
+                "Mastering display metadata" => {
+                    let point = |x: &str, y: &str| Some((fraction(&data[x], 50_000)?, fraction(&data[y], 50_000)?));
+
+                    let display = (|| Some(MasteringDisplay {
+                        red: point("red_x", "red_y")?,
+                        green: point("green_x", "green_y")?,
+                        blue: point("blue_x", "blue_y")?,
+                        white_point: point("white_point_x", "white_point_y")?,
+                        luminance: (fraction(&data["max_luminance"], 10_000)?, fraction(&data["min_luminance"], 10_000)?),
+                    }))();
+
+                    self.mastering_display = self.mastering_display.take().or(display);
+                },
+                "Content light level metadata" => {
+                    let level = data["max_content"].as_u64().zip(data["max_average"].as_u64());
+                    self.light_level = self.light_level.or(level);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Check if the video uses PQ or HLG transfer
+    pub fn is_hdr(&self) -> bool {
+        matches!(self.color_transfer.as_deref(), Some("smpte2084" | "arib-std-b67"))
+    }
+
+    /// Input options to keep frames as they are stored and the rotation as display matrix,
+    /// otherwise ffmpeg rotates the frames of re-encoded parts but not of the copied ones
+    pub fn input_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec!["-noautorotate".into()];
+
+        if let Some(rotation) = self.rotation {
+            args.extend(["-display_rotation:v:0".into(), rotation.to_string()]);
+        }
+
+        args
+    }
+
+    /// Output options for the encoder, the second value is a warning if something cannot be kept
+    pub fn encoder_args(&self, encoder: Option<&str>) -> (Vec<String>, Option<String>) {
+        let mut args: Vec<String> = vec![];
+
+        let colors = [
+            ("-color_primaries", &self.color_primaries),
+            ("-color_trc", &self.color_transfer),
+            ("-colorspace", &self.color_space),
+            ("-color_range", &self.color_range),
+        ];
+
+        for (option, value) in colors {
+            if let Some(value) = value {
+                args.extend([option.into(), value.clone()]);
+            }
+        }
+
+        if self.mastering_display.is_none() && self.light_level.is_none() {
+            return (args, None);
+        }
+
+        if encoder != Some("libx265") {
+            let warning = format!(
+                "mastering display and light level of HDR video are only kept with libx265, not with {}",
+                encoder.unwrap_or("the default encoder"),
+            );
+
+            return (args, Some(warning));
+        }
+
+        let mut params: Vec<String> = vec!["hdr10=1".into(), "repeat-headers=1".into()];
+
+        if let Some(x) = &self.mastering_display {
+            params.push(format!(
+                "master-display=G({},{})B({},{})R({},{})WP({},{})L({},{})",
+                x.green.0, x.green.1, x.blue.0, x.blue.1, x.red.0, x.red.1,
+                x.white_point.0, x.white_point.1, x.luminance.0, x.luminance.1,
+            ));
+        }
+
+        if let Some((max_content, max_average)) = self.light_level {
+            params.push(format!("max-cll={},{}", max_content, max_average));
+        }
+
+        args.extend(["-x265-params".into(), params.join(":")]);
+        (args, None)
+    }
+}
+
+impl VideoFile {
+    /// Get rotation, color properties and HDR side data of the first video stream
+    ///
+    /// Side data can be stored in the container (stream) or in the bitstream (first frame)
+    pub fn get_video_props(&self) -> Result<VideoProps, (String, u8)> {
+        let data = self.probe_json(&["-select_streams", "v:0", "-show_streams"])?;
+        let stream = &data["streams"][0];
+
+        let mut props = VideoProps::from_stream(stream);
+        props.add_side_data(&stream["side_data_list"].as_array().cloned().unwrap_or_default());
+
+        // first few packets are enough to get the first frame out of the decoder
+        let data = self.probe_json(&["-select_streams", "v:0", "-read_intervals", "%+#5", "-show_frames"])?;
+        props.add_side_data(&data["frames"][0]["side_data_list"].as_array().cloned().unwrap_or_default());
+
+        Ok(props)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_props() {
+        let stream = serde_json::json!({
+            "color_primaries": "bt2020",
+            "color_transfer": "smpte2084",
+            "color_space": "bt2020nc",
+            "color_range": "unknown",
+            "side_data_list": [{ "side_data_type": "Display Matrix", "rotation": -90 }],
+        });
+
+        let mut props = VideoProps::from_stream(&stream);
+        props.add_side_data(stream["side_data_list"].as_array().unwrap());
+        props.add_side_data(&[
+            serde_json::json!({
+                "side_data_type": "Mastering display metadata",
+                "red_x": "35400/50000", "red_y": "14600/50000",
+                "green_x": "8500/50000", "green_y": "39850/50000",
+                "blue_x": "6550/50000", "blue_y": "2300/50000",
+                "white_point_x": "15635/50000", "white_point_y": "16450/50000",
+                "min_luminance": "50/10000", "max_luminance": "10000000/10000",
+            }),
+            serde_json::json!({ "side_data_type": "Content light level metadata", "max_content": 1000, "max_average": 400 }),
+        ]);
+
+        assert!(props.is_hdr());
+        assert_eq!(props.rotation, Some(-90));
+        assert_eq!(props.color_range, None);
+        assert_eq!(props.input_args(), ["-noautorotate", "-display_rotation:v:0", "-90"]);
+
+        let (args, warning) = props.encoder_args(Some("libx265"));
+        assert_eq!(warning, None);
+        assert_eq!(args, [
+            "-color_primaries", "bt2020", "-color_trc", "smpte2084", "-colorspace", "bt2020nc",
+            "-x265-params",
+            "hdr10=1:repeat-headers=1:master-display=G(8500,39850)B(6550,2300)R(35400,14600)WP(15635,16450)L(10000000,50):max-cll=1000,400",
+        ]);
+
+        let (args, warning) = props.encoder_args(Some("libx264"));
+        assert_eq!(args.len(), 6);
+        assert!(warning.is_some());
+    }
+}
@@ -0,0 +1,350 @@
+use serde::Serialize;
+
+use super::{secs, Span, VideoFile};
+use crate::progress;
+
+/// Timing of one stream from its packets
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct StreamTiming {
+    /// `video` or `audio`
+    pub codec_type: String,
+
+    /// Time of the first packet relative to the start of the file, in micros
+    pub start: u64,
+
+    /// From the start of the first packet to the end of the last one, in micros
+    pub duration: u64,
+
+    /// Missing time between packets as start and length
+    pub gaps: Vec<Span>,
+
+    /// Time covered by more than one packet as start and length
+    pub overlaps: Vec<Span>,
+
+    /// Typical packet duration
+    pub packet_duration: u64,
+}
+
+/// Result of A/V sync verification
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    pub streams: Vec<StreamTiming>,
+
+    /// Start of audio relative to start of video, positive when audio starts later
+    pub av_offset: Option<i64>,
+}
+
+/// Compute timing from packets as (pts, duration), pts do not have to be sorted as packets are
+/// stored in decoding order, differences up to tolerance are ignored
+pub fn stream_timing(codec_type: &str, packets: &[(u64, u64)], tolerance: u64) -> Option<StreamTiming> {
+    let mut packets = packets.to_vec();
+    packets.sort();
+
+    let (first, _) = *packets.first()?;
+    let end = packets.iter().map(|(pts, duration)| pts + duration).max()?;
+
+    let mut timing = StreamTiming {
+        codec_type: codec_type.into(),
+        start: first,
+        duration: end - first,
+        ..Default::default()
+    };
+
+    // median is not thrown off by the odd short packet at the end
+    let mut durations: Vec<u64> = packets.iter().map(|(_, duration)| *duration).collect();
+    durations.sort();
+    timing.packet_duration = durations[durations.len() / 2];
+
+    for pair in packets.windows(2) {
+        let expected = pair[0].0 + pair[0].1;
+        let next = pair[1].0;
+
+        if next > expected + tolerance {
+            timing.gaps.push((expected, next - expected));
+        } else if next + tolerance < expected {
+            timing.overlaps.push((next, expected - next));
+        }
+    }
+
+    Some(timing)
+}
+
+impl SyncReport {
+    fn stream(&self, codec_type: &str) -> Option<&StreamTiming> {
+        self.streams.iter().find(|x| x.codec_type == codec_type)
+    }
+
+    /// Largest A/V offset that is not considered a problem, one video frame or audio packet
+    fn tolerance(&self) -> u64 {
+        self.streams.iter().map(|x| x.packet_duration).max().unwrap_or(40_000)
+    }
+
+    /// Describe the problems found, empty if there are none
+    ///
+    /// Streams should be as long as expected and audio should end with the video, otherwise the
+    /// audio drifts or was cut at a different time
+    pub fn problems(&self, expected_length: Option<u64>, expected_offset: i64) -> Vec<String> {
+        let mut problems: Vec<String> = vec![];
+        let tolerance = self.tolerance();
+
+        for stream in &self.streams {
+            if let Some(expected) = expected_length.filter(|x| x.abs_diff(stream.duration) > tolerance) {
+                problems.push(format!(
+                    "Stream {} is {} long, expected {}",
+                    stream.codec_type, secs(stream.duration as i64), secs(expected as i64),
+                ));
+            }
+
+            for (start, length) in &stream.gaps {
+                problems.push(format!("Gap in {} at {} ({} long)", stream.codec_type, secs(*start as i64), secs(*length as i64)));
+            }
+
+            for (start, length) in &stream.overlaps {
+                problems.push(format!("Overlap in {} at {} ({} long)", stream.codec_type, secs(*start as i64), secs(*length as i64)));
+            }
+        }
+
+        if let Some(offset) = self.av_offset {
+            if offset.abs_diff(expected_offset) > tolerance {
+                problems.push(format!("Audio is offset by {} against video, expected {}", secs(offset), secs(expected_offset)));
+            }
+        }
+
+        if let (Some(video), Some(audio)) = (self.stream("video"), self.stream("audio")) {
+            let drift = (audio.start + audio.duration) as i64 - (video.start + video.duration) as i64;
+
+            if drift.unsigned_abs() > tolerance {
+                let side = if drift > 0 { "after" } else { "before" };
+                problems.push(format!("Audio ends {} {} video", secs(drift.abs()), side));
+            }
+        }
+
+        problems
+    }
+
+    /// Print timing of each stream, expected length is printed next to the durations
+    pub fn print(&self, expected_length: Option<u64>) {
+        for stream in &self.streams {
+            let expected = expected_length.map_or(String::new(), |x| format!(" (expected {})", secs(x as i64)));
+
+            println!(
+                "Stream {}: starts at {}, duration {}{}",
+                stream.codec_type, secs(stream.start as i64), secs(stream.duration as i64), expected,
+            );
+        }
+
+        if let Some(offset) = self.av_offset {
+            println!("A/V offset: {}", secs(offset));
+        }
+    }
+}
+
+impl VideoFile {
+    /// Get pts and duration of every packet of the stream, relative to the start of the file
+    fn get_packets(&self, stream: &str) -> Result<Vec<(u64, u64)>, (String, u8)> {
+        let start_time = self.get_start_time()?;
+        let data = self.probe_json(&["-select_streams", stream, "-show_entries", "packet=pts_time,duration_time"])?;
+
+        let time = |x: &serde_json::Value| x.as_str().and_then(|x| x.parse::<f64>().ok());
+
+        Ok(data["packets"].as_array().cloned().unwrap_or_default().iter()
+            .filter_map(|x| Some((time(&x["pts_time"])?, time(&x["duration_time"]).unwrap_or(0.0))))
+            .map(|(pts, duration)| (
+                ((pts * 1_000_000.0).round() as i64 - start_time).max(0) as u64,
+                (duration * 1_000_000.0).round() as u64,
+            ))
+            .collect())
+    }
+
+    /// Get timing of the first video and audio stream
+    pub fn get_sync_report(&self) -> Result<SyncReport, (String, u8)> {
+        let mut report = SyncReport::default();
+
+        for (codec_type, stream) in [("video", "v:0"), ("audio", "a:0")] {
+            // rounding to micros can make neighbouring packets off by a bit
+            if let Some(timing) = stream_timing(codec_type, &self.get_packets(stream)?, 1_000) {
+                report.streams.push(timing);
+            }
+        }
+
+        if let (Some(video), Some(audio)) = (report.stream("video"), report.stream("audio")) {
+            report.av_offset = Some(audio.start as i64 - video.start as i64);
+        }
+
+        Ok(report)
+    }
+
+    /// Check A/V sync of this file and print the report, problems make it fail
+    pub fn verify_sync(&self, expected_length: Option<u64>, expected_offset: i64) -> crate::ExitResult {
+        let report = self.get_sync_report().map_err(|x| self.report.fail(x))?;
+
+        if !self.report.is_json() {
+            println!("Verifying {:?}", self.path);
+            report.print(expected_length);
+        }
+
+        let problems = report.problems(expected_length, expected_offset);
+        for problem in &problems {
+            self.report.error(problem);
+        }
+
+        self.report.sync(&self.path.to_string_lossy(), report);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(1)
+        }
+    }
+
+    /// Get duration of one frame of the first video stream
+    pub fn get_frame_duration(&self) -> Result<Option<u64>, (String, u8)> {
+        let data = self.probe_json(&["-select_streams", "v:0", "-show_entries", "stream=avg_frame_rate"])?;
+
+        // frame rate is a fraction like 30000/1001, 0/0 if unknown
+        let rate = data["streams"][0]["avg_frame_rate"].as_str().unwrap_or_default().to_string();
+        Ok(rate.split_once('/')
+            .and_then(|(num, den)| Some((num.parse::<u64>().ok()?, den.parse::<u64>().ok()?)))
+            .filter(|(num, den)| *num > 0 && *den > 0)
+            .map(|(num, den)| 1_000_000 * den / num))
+    }
+
+    /// Check that this file is as long as expected (within one frame) and that it decodes without
+    /// any errors, which are most likely at the points where it was cut or joined
+    pub fn validate(&self, expected_length: Option<u64>) -> crate::ExitResult {
+        let print = |x: (String, u8)| self.report.fail(x);
+
+        eprintln!("Validating {:?}", self.path);
+
+        if let Some(expected) = expected_length {
+            let duration = self.get_duration().map_err(print)?;
+            let tolerance = self.get_frame_duration().map_err(print)?.unwrap_or(40_000);
+
+            if duration.abs_diff(expected) > tolerance {
+                self.report.error(format!("Output is {} long, expected {}", secs(duration as i64), secs(expected as i64)));
+                return Err(1);
+            }
+        }
+
+        // decode everything and throw it away, level of each message is printed so errors can be
+        // told apart from whatever else the loglevel lets through
+        let mut cmd = self.ffmpeg_cmd();
+        cmd.args(["-loglevel", &format!("level+{}", self.config.loglevel())]);
+        cmd.arg("-i").arg(&self.path);
+        cmd.args(["-f", "null", "-"]);
+
+        let length = self.get_duration().unwrap_or(0);
+        let (status, output) = progress::run_ffmpeg_stderr(&cmd, length, self.progress, &self.cancel)?;
+
+        let errors: Vec<&str> = output.lines()
+            .filter(|x| ["[error] ", "[fatal] ", "[panic] "].iter().any(|level| x.contains(level)))
+            .collect();
+
+        if status.is_err() || !errors.is_empty() {
+            // ffmpeg failing on its own may not log anything as error
+            let details = match errors.is_empty() {
+                true => output.trim_end().to_string(),
+                false => errors.join("\n"),
+            };
+
+            self.report.error(format!("Output does not decode cleanly:\n{}", details));
+            return Err(1);
+        }
+
+        Ok(())
+    }
+
+    /// Run the checks requested for outputs on dest after it was written, expected length is
+    /// only computed if needed
+    pub(super) fn verify_output(&self, dest: &str, expected_length: impl FnOnce() -> Option<u64>, expected_offset: i64) -> crate::ExitResult {
+        if !(self.verify || self.validate) || self.dry_run {
+            return Ok(());
+        }
+
+        let output = self.with_path(dest);
+        let expected_length = expected_length();
+
+        if self.verify {
+            output.verify_sync(expected_length, expected_offset)?;
+        }
+
+        if self.validate {
+            output.validate(expected_length)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_timing() {
+        // b-frames are out of order, one packet is missing and one overlaps
+        let packets = [(0, 40), (80, 40), (40, 40), (200, 40), (230, 40), (270, 40)];
+        let timing = stream_timing("video", &packets, 5).unwrap();
+
+        assert_eq!(timing.start, 0);
+        assert_eq!(timing.duration, 310);
+        assert_eq!(timing.packet_duration, 40);
+        assert_eq!(timing.gaps, vec![(120, 80)]);
+        assert_eq!(timing.overlaps, vec![(230, 10)]);
+
+        assert_eq!(stream_timing("audio", &[], 5), None);
+    }
+
+    #[test]
+    fn test_sync_problems() {
+        let stream = |codec_type: &str, start: u64| StreamTiming {
+            codec_type: codec_type.into(),
+            start,
+            duration: 1_000_000,
+            packet_duration: 20_000,
+            ..Default::default()
+        };
+
+        let mut report = SyncReport {
+            streams: vec![stream("video", 0), stream("audio", 10_000)],
+            av_offset: Some(10_000),
+        };
+        assert!(report.problems(None, 0).is_empty());
+
+        report.av_offset = Some(100_000);
+        assert_eq!(report.problems(None, 0).len(), 1);
+        assert!(report.problems(None, 90_000).is_empty());
+
+        report.streams[1].gaps.push((500_000, 21_000));
+        assert_eq!(report.problems(None, 90_000), vec!["Gap in audio at 0.500s (0.021s long)".to_string()]);
+    }
+
+    #[test]
+    fn test_duration_problems() {
+        let stream = |codec_type: &str, duration: u64| StreamTiming {
+            codec_type: codec_type.into(),
+            duration,
+            packet_duration: 20_000,
+            ..Default::default()
+        };
+
+        let mut report = SyncReport {
+            streams: vec![stream("video", 1_000_000), stream("audio", 1_010_000)],
+            av_offset: Some(0),
+        };
+        assert!(report.problems(Some(1_000_000), 0).is_empty());
+
+        // audio drifted and ends early
+        report.streams[1].duration = 900_000;
+        assert_eq!(report.problems(Some(1_000_000), 0), vec![
+            "Stream audio is 0.900s long, expected 1.000s".to_string(),
+            "Audio ends 0.100s before video".to_string(),
+        ]);
+
+        // both are cut too long, audio still ends with video
+        report.streams[0].duration = 1_500_000;
+        report.streams[1].duration = 1_500_000;
+        assert_eq!(report.problems(Some(1_000_000), 0).len(), 2);
+        assert!(report.problems(None, 0).is_empty());
+    }
+}